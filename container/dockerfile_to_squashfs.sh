@@ -5,6 +5,9 @@ set -e
 DOCKER_BUILDKIT=1 docker build -t alpine-snow -f $1 .
 docker create --name alpine-snow alpine-snow
 
+# Entrypoint, Cmd, Env, WorkingDir, User and StopSignal for `snow pack --config`.
+docker inspect --format '{{json .Config}}' alpine-snow > "${2%.squashfs}.config.json"
+
 rm -rf /tmp/alpine-snow
 mkdir /tmp/alpine-snow
 
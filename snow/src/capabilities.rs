@@ -0,0 +1,86 @@
+use anyhow::{Context, Result};
+use caps::{CapSet, Capability, CapsHashSet};
+use std::str::FromStr;
+
+/// What Docker grants a container unless told otherwise.
+const DEFAULT_CAPABILITIES: [Capability; 14] = [
+    Capability::CAP_CHOWN,
+    Capability::CAP_DAC_OVERRIDE,
+    Capability::CAP_FSETID,
+    Capability::CAP_FOWNER,
+    // The device cgroup keeps the nodes it makes from being opened.
+    Capability::CAP_MKNOD,
+    Capability::CAP_NET_RAW,
+    Capability::CAP_SETGID,
+    Capability::CAP_SETUID,
+    Capability::CAP_SETFCAP,
+    Capability::CAP_SETPCAP,
+    Capability::CAP_NET_BIND_SERVICE,
+    Capability::CAP_SYS_CHROOT,
+    Capability::CAP_KILL,
+    Capability::CAP_AUDIT_WRITE,
+];
+
+/// Parses capabilities like Docker takes them, in any case and with or without
+/// the `CAP_` prefix. Returns whether `ALL` was among them as well.
+fn parse(names: &[String]) -> Result<(CapsHashSet, bool)> {
+    let mut capabilities = CapsHashSet::new();
+    let mut all = false;
+
+    for name in names {
+        if name.eq_ignore_ascii_case("all") {
+            all = true;
+            continue;
+        }
+        let capability = Capability::from_str(&caps::to_canonical(name))
+            .with_context(|| format!("unknown capability {name}"))?;
+        capabilities.insert(capability);
+    }
+
+    Ok((capabilities, all))
+}
+
+/// The capabilities of the container, Docker's defaults with `add` added and
+/// `drop` dropped.
+pub fn resolve(add: &[String], drop: &[String]) -> Result<CapsHashSet> {
+    let (add, add_all) = parse(add)?;
+    let (drop, drop_all) = parse(drop)?;
+
+    let mut capabilities = if add_all {
+        caps::runtime::thread_all_supported()
+    } else if drop_all {
+        CapsHashSet::new()
+    } else {
+        DEFAULT_CAPABILITIES.into_iter().collect()
+    };
+    if !add_all {
+        capabilities.extend(add);
+    }
+    if !drop_all {
+        capabilities.retain(|capability| !drop.contains(capability));
+    }
+
+    Ok(capabilities)
+}
+
+/// Limits the calling process to `capabilities` in every set, for good.
+/// Switching users must have kept the capabilities, which are taken from
+/// the permitted set.
+pub fn apply(capabilities: &CapsHashSet) -> Result<()> {
+    let permitted = caps::read(None, CapSet::Permitted)?;
+    // Dropping from the bounding set takes CAP_SETPCAP.
+    caps::set(None, CapSet::Effective, &permitted)?;
+    for capability in caps::read(None, CapSet::Bounding)? {
+        if !capabilities.contains(&capability) {
+            caps::drop(None, CapSet::Bounding, capability)?;
+        }
+    }
+
+    caps::clear(None, CapSet::Ambient)?;
+    caps::clear(None, CapSet::Inheritable)?;
+    let kept: CapsHashSet = permitted.intersection(capabilities).copied().collect();
+    caps::set(None, CapSet::Effective, &kept)?;
+    caps::set(None, CapSet::Permitted, &kept)?;
+
+    Ok(())
+}
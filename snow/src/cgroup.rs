@@ -0,0 +1,463 @@
+use anyhow::{bail, Context, Result};
+use log::info;
+use nix::errno::Errno;
+use nix::libc;
+use nix::sys::stat;
+use std::fs::File;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+use std::os::unix::fs::{FileTypeExt, MetadataExt};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// The cpu.max period, quotas are given in microseconds of it.
+const CPU_PERIOD: u64 = 100_000;
+/// The smallest quota the kernel takes, in microseconds.
+const MIN_CPU_QUOTA: u64 = 1000;
+
+/// Resource limits of the container, all unlimited by default.
+#[derive(Debug, Default)]
+pub struct Limits {
+    pub memory: Option<u64>,
+    pub cpus: Option<f64>,
+    pub pids: Option<u64>,
+    pub io_weight: Option<u16>,
+}
+
+impl Limits {
+    /// The controller, interface file and value for each limit that is set.
+    fn settings(&self) -> Vec<(&'static str, &'static str, String)> {
+        let mut settings = vec![];
+        if let Some(memory) = self.memory {
+            settings.push(("memory", "memory.max", memory.to_string()));
+        }
+        if let Some(cpus) = self.cpus {
+            let quota = (cpus * CPU_PERIOD as f64) as u64;
+            settings.push(("cpu", "cpu.max", format!("{quota} {CPU_PERIOD}")));
+        }
+        if let Some(pids) = self.pids {
+            settings.push(("pids", "pids.max", pids.to_string()));
+        }
+        if let Some(io_weight) = self.io_weight {
+            settings.push(("io", "io.weight", format!("default {io_weight}")));
+        }
+
+        settings
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.settings().is_empty()
+    }
+}
+
+/// Parses a byte count with an optional binary k, m or g suffix, e.g. `512m`.
+pub fn parse_size(size: &str) -> Result<u64> {
+    let lowercase = size.to_ascii_lowercase();
+    let (number, shift) = match lowercase.strip_suffix('b').unwrap_or(&lowercase) {
+        number if number.ends_with('k') => (&number[..number.len() - 1], 10),
+        number if number.ends_with('m') => (&number[..number.len() - 1], 20),
+        number if number.ends_with('g') => (&number[..number.len() - 1], 30),
+        number => (number, 0),
+    };
+
+    let number: u64 = number
+        .parse()
+        .with_context(|| format!("invalid size {size}"))?;
+    number
+        .checked_mul(1 << shift)
+        .with_context(|| format!("size {size} is too large"))
+}
+
+pub fn parse_cpus(cpus: &str) -> Result<f64> {
+    let cpus: f64 = cpus.parse()?;
+    if cpus.is_nan() || cpus <= 0.0 {
+        bail!("expected a positive number of cpus");
+    }
+    if ((cpus * CPU_PERIOD as f64) as u64) < MIN_CPU_QUOTA {
+        bail!(
+            "{cpus} cpus is less than the kernel allows, at least {} is needed",
+            MIN_CPU_QUOTA as f64 / CPU_PERIOD as f64
+        );
+    }
+
+    Ok(cpus)
+}
+
+/// Where cgroup2 is mounted, /sys/fs/cgroup/unified on hybrid hosts.
+fn cgroup2_root() -> Result<PathBuf> {
+    let mounts = std::fs::read_to_string("/proc/self/mounts")?;
+
+    mounts
+        .lines()
+        .map(|line| line.split_whitespace().collect::<Vec<_>>())
+        .find(|fields| fields.get(2) == Some(&"cgroup2"))
+        .map(|fields| PathBuf::from(fields[1]))
+        .context("cgroup2 is not mounted")
+}
+
+/// The cgroup we run in, relative to the cgroup2 root.
+fn own_cgroup() -> Result<PathBuf> {
+    let cgroups = std::fs::read_to_string("/proc/self/cgroup")?;
+
+    cgroups
+        .lines()
+        .find_map(|line| line.strip_prefix("0::"))
+        .map(PathBuf::from)
+        .context("not in a cgroup2 hierarchy")
+}
+
+/// Where container cgroups go unless `--cgroup-parent` says otherwise.
+/// Regular users may only create cgroups in the subtree systemd delegates to
+/// them, which is where we run, so rootless containers go next to us.
+pub fn default_parent(rootless: bool) -> Result<PathBuf> {
+    if !rootless {
+        return Ok(PathBuf::from("snow.slice"));
+    }
+
+    let own = own_cgroup()?;
+    Ok(own.parent().unwrap_or(&own).to_path_buf())
+}
+
+/// The container's own cgroup, removed when the snow that created it drops it.
+pub struct Cgroup {
+    path: PathBuf,
+}
+
+impl Cgroup {
+    /// Creates a cgroup for this container under `parent`, relative to the
+    /// cgroup2 root, with `limits` applied.
+    pub fn create(parent: &Path, limits: &Limits) -> Result<Self> {
+        let root = cgroup2_root()?;
+        let parent = root.join(parent.strip_prefix("/").unwrap_or(parent));
+        std::fs::create_dir_all(&parent)
+            .with_context(|| format!("failed creating {}", parent.display()))?;
+
+        // Controllers have to be enabled all the way down for the limits to
+        // exist, the ones we may not touch have to be delegated with them.
+        let settings = limits.settings();
+        let mut ancestors: Vec<_> = parent
+            .ancestors()
+            .take_while(|ancestor| ancestor.starts_with(&root))
+            .collect();
+        ancestors.reverse();
+        for ancestor in ancestors {
+            for (controller, _, _) in &settings {
+                let _ = std::fs::write(
+                    ancestor.join("cgroup.subtree_control"),
+                    format!("+{controller}"),
+                );
+            }
+        }
+
+        let path = parent.join(format!("snow-{}", std::process::id()));
+        info!("creating cgroup {}", path.display());
+        std::fs::create_dir(&path)
+            .with_context(|| format!("failed creating {}", path.display()))?;
+        let cgroup = Cgroup { path };
+
+        for (controller, file, value) in settings {
+            std::fs::write(cgroup.path.join(file), &value).with_context(|| {
+                format!(
+                    "failed setting {file} to {value}, is the {controller} controller available?"
+                )
+            })?;
+        }
+
+        Ok(cgroup)
+    }
+
+    /// Moves the calling process, the container, into the cgroup. Removing it
+    /// stays with the process on the host. Returns the cgroup directory, to
+    /// limit the devices in once the cgroup ns hides it.
+    pub fn enter(self) -> Result<File> {
+        let directory = File::open(&self.path)
+            .with_context(|| format!("failed opening {}", self.path.display()))?;
+        std::fs::write(self.path.join("cgroup.procs"), "0")
+            .with_context(|| format!("failed joining {}", self.path.display()))?;
+        std::mem::forget(self);
+
+        Ok(directory)
+    }
+}
+
+impl Drop for Cgroup {
+    fn drop(&mut self) {
+        // Whatever is left, like orphans of a container sharing the host PID
+        // namespace. They take a moment to leave the cgroup once killed.
+        let _ = std::fs::write(self.path.join("cgroup.kill"), "1");
+        for _ in 0..100 {
+            match std::fs::remove_dir(&self.path) {
+                Err(err) if err.raw_os_error() == Some(nix::libc::EBUSY) => {
+                    std::thread::sleep(Duration::from_millis(10))
+                }
+                _ => return,
+            }
+        }
+    }
+}
+
+/// Whether a device is a block or a character one, numbered as device cgroup
+/// programs see it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeviceKind {
+    Block = 1,
+    Char = 2,
+}
+
+/// A device the container may open, every minor of `major` without `minor`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Device {
+    pub kind: DeviceKind,
+    pub major: u32,
+    pub minor: Option<u32>,
+}
+
+impl Device {
+    /// The device the node at `path` stands for, None if it is no device node.
+    pub fn of(path: &Path) -> Result<Option<Self>> {
+        let metadata = std::fs::metadata(path)
+            .with_context(|| format!("failed reading {}", path.display()))?;
+        let kind = match metadata.file_type() {
+            file_type if file_type.is_block_device() => DeviceKind::Block,
+            file_type if file_type.is_char_device() => DeviceKind::Char,
+            _ => return Ok(None),
+        };
+
+        Ok(Some(Device {
+            kind,
+            major: stat::major(metadata.rdev()) as u32,
+            minor: Some(stat::minor(metadata.rdev()) as u32),
+        }))
+    }
+}
+
+// From include/uapi/linux/bpf.h, libc only has classic BPF.
+const BPF_PROG_LOAD: libc::c_long = 5;
+const BPF_PROG_ATTACH: libc::c_long = 8;
+const BPF_PROG_TYPE_CGROUP_DEVICE: u32 = 15;
+const BPF_CGROUP_DEVICE: u32 = 6;
+const BPF_F_ALLOW_MULTI: u32 = 2;
+const BPF_DEVCG_ACC_MKNOD: i32 = 1;
+
+const BPF_LDX_MEM_W: u8 = 0x61;
+const BPF_ALU64_AND_K: u8 = 0x57;
+const BPF_ALU64_RSH_K: u8 = 0x77;
+const BPF_ALU64_MOV_K: u8 = 0xb7;
+const BPF_JNE_K: u8 = 0x55;
+const BPF_EXIT: u8 = 0x95;
+
+/// struct bpf_insn, the registers are 4 bits each.
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+struct Instruction {
+    code: u8,
+    registers: u8,
+    offset: i16,
+    immediate: i32,
+}
+
+fn instruction(code: u8, destination: u8, source: u8, offset: i16, immediate: i32) -> Instruction {
+    Instruction {
+        code,
+        registers: source << 4 | destination,
+        offset,
+        immediate,
+    }
+}
+
+/// The part of union bpf_attr BPF_PROG_LOAD reads.
+#[repr(C)]
+struct ProgLoadAttr {
+    prog_type: u32,
+    insn_cnt: u32,
+    insns: u64,
+    license: u64,
+    log_level: u32,
+    log_size: u32,
+    log_buf: u64,
+    kern_version: u32,
+    prog_flags: u32,
+}
+
+/// The part of union bpf_attr BPF_PROG_ATTACH reads.
+#[repr(C)]
+struct ProgAttachAttr {
+    target_fd: u32,
+    attach_bpf_fd: u32,
+    attach_type: u32,
+    attach_flags: u32,
+}
+
+/// A device cgroup program allowing to create any device node, like Docker
+/// does, but to open only `devices`.
+fn device_program(devices: &[Device]) -> Vec<Instruction> {
+    let allow = |allowed: bool| {
+        [
+            instruction(BPF_ALU64_MOV_K, 0, 0, 0, allowed as i32),
+            instruction(BPF_EXIT, 0, 0, 0, 0),
+        ]
+    };
+
+    // r1 points at struct bpf_cgroup_dev_ctx, its access type has the access
+    // in the upper half and the kind in the lower.
+    let mut program = vec![
+        instruction(BPF_LDX_MEM_W, 2, 1, 0, 0),
+        instruction(BPF_ALU64_AND_K, 2, 0, 0, 0xffff),
+        instruction(BPF_LDX_MEM_W, 3, 1, 0, 0),
+        instruction(BPF_ALU64_RSH_K, 3, 0, 0, 16),
+        instruction(BPF_LDX_MEM_W, 4, 1, 4, 0),
+        instruction(BPF_LDX_MEM_W, 5, 1, 8, 0),
+        instruction(BPF_JNE_K, 3, 0, 2, BPF_DEVCG_ACC_MKNOD),
+    ];
+    program.extend(allow(true));
+
+    for device in devices {
+        let mut checks = vec![(2, device.kind as i32), (4, device.major as i32)];
+        checks.extend(device.minor.map(|minor| (5, minor as i32)));
+        // A mismatch skips the checks left and the return.
+        for (index, (register, value)) in checks.iter().enumerate() {
+            let skip = checks.len() - index + 1;
+            program.push(instruction(BPF_JNE_K, *register, 0, skip as i16, *value));
+        }
+        program.extend(allow(true));
+    }
+    program.extend(allow(false));
+
+    program
+}
+
+/// Lets the processes in the cgroup `directory` open only `devices`, as the
+/// devices cgroup Docker sets up.
+pub fn allow_only_devices(directory: &File, devices: &[Device]) -> Result<()> {
+    let program = device_program(devices);
+    // It calls no helpers a license would matter to.
+    let license = c"GPL";
+    let load = ProgLoadAttr {
+        prog_type: BPF_PROG_TYPE_CGROUP_DEVICE,
+        insn_cnt: program.len() as u32,
+        insns: program.as_ptr() as u64,
+        license: license.as_ptr() as u64,
+        log_level: 0,
+        log_size: 0,
+        log_buf: 0,
+        kern_version: 0,
+        prog_flags: 0,
+    };
+    // SAFETY: the attributes and what they point at outlive the call.
+    let fd = Errno::result(unsafe {
+        libc::syscall(
+            libc::SYS_bpf,
+            BPF_PROG_LOAD,
+            &load,
+            std::mem::size_of::<ProgLoadAttr>(),
+        )
+    })
+    .context("failed loading the device cgroup program")?;
+    // SAFETY: the kernel just gave us the program's new fd.
+    let program_fd = unsafe { OwnedFd::from_raw_fd(fd as i32) };
+
+    // Programs of the cgroups above still apply along with ours.
+    let attach = ProgAttachAttr {
+        target_fd: directory.as_raw_fd() as u32,
+        attach_bpf_fd: program_fd.as_raw_fd() as u32,
+        attach_type: BPF_CGROUP_DEVICE,
+        attach_flags: BPF_F_ALLOW_MULTI,
+    };
+    // SAFETY: the attributes outlive the call.
+    Errno::result(unsafe {
+        libc::syscall(
+            libc::SYS_bpf,
+            BPF_PROG_ATTACH,
+            &attach,
+            std::mem::size_of::<ProgAttachAttr>(),
+        )
+    })
+    .context("failed attaching the device cgroup program")?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MKNOD: u32 = 1;
+    const READ: u32 = 2;
+    const WRITE: u32 = 4;
+
+    const NULL: Device = Device {
+        kind: DeviceKind::Char,
+        major: 1,
+        minor: Some(3),
+    };
+    const PTS: Device = Device {
+        kind: DeviceKind::Char,
+        major: 136,
+        minor: None,
+    };
+    const LOOP: Device = Device {
+        kind: DeviceKind::Block,
+        major: 7,
+        minor: Some(0),
+    };
+
+    /// Runs `program` on a device access the way the kernel does.
+    fn run(program: &[Instruction], kind: DeviceKind, access: u32, major: u32, minor: u32) -> u64 {
+        let context = [access << 16 | kind as u32, major, minor];
+        let mut registers = [0u64; 11];
+        let mut pc = 0;
+        loop {
+            let instruction = program[pc];
+            let destination = (instruction.registers & 0xf) as usize;
+            let immediate = instruction.immediate as i64 as u64;
+            pc += 1;
+            match instruction.code {
+                BPF_LDX_MEM_W => {
+                    assert_eq!(instruction.registers >> 4, 1);
+                    registers[destination] = context[instruction.offset as usize / 4] as u64;
+                }
+                BPF_ALU64_AND_K => registers[destination] &= immediate,
+                BPF_ALU64_RSH_K => registers[destination] >>= immediate,
+                BPF_ALU64_MOV_K => registers[destination] = immediate,
+                BPF_JNE_K => {
+                    if registers[destination] != immediate {
+                        pc += instruction.offset as usize;
+                    }
+                }
+                BPF_EXIT => return registers[0],
+                code => panic!("unexpected instruction {code:#x}"),
+            }
+        }
+    }
+
+    #[test]
+    fn creates_any_device() {
+        let program = device_program(&[NULL]);
+
+        assert_eq!(run(&program, DeviceKind::Block, MKNOD, 8, 0), 1);
+        assert_eq!(run(&program, DeviceKind::Char, MKNOD, 10, 200), 1);
+        assert_eq!(run(&program, DeviceKind::Block, READ, 8, 0), 0);
+        assert_eq!(run(&program, DeviceKind::Block, MKNOD | READ, 8, 0), 0);
+    }
+
+    #[test]
+    fn opens_only_allowed_devices() {
+        let program = device_program(&[NULL, PTS, LOOP]);
+
+        assert_eq!(run(&program, DeviceKind::Char, READ | WRITE, 1, 3), 1);
+        assert_eq!(run(&program, DeviceKind::Char, READ, 1, 5), 0);
+        assert_eq!(run(&program, DeviceKind::Char, READ | WRITE, 136, 0), 1);
+        assert_eq!(run(&program, DeviceKind::Char, WRITE, 136, 42), 1);
+        assert_eq!(run(&program, DeviceKind::Block, READ, 136, 42), 0);
+        assert_eq!(run(&program, DeviceKind::Block, READ | WRITE, 7, 0), 1);
+        assert_eq!(run(&program, DeviceKind::Block, READ, 7, 1), 0);
+        assert_eq!(run(&program, DeviceKind::Char, READ, 7, 0), 0);
+    }
+
+    #[test]
+    fn opens_nothing_by_default() {
+        let program = device_program(&[]);
+
+        assert_eq!(run(&program, DeviceKind::Char, READ, 1, 3), 0);
+        assert_eq!(run(&program, DeviceKind::Char, MKNOD, 1, 3), 1);
+    }
+}
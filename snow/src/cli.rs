@@ -0,0 +1,286 @@
+use crate::cgroup;
+use crate::mount::{DevMode, DeviceMapping};
+use crate::network::NetworkMode;
+use crate::ports::PortMapping;
+use crate::userns::IdMap;
+use crate::volume::Volume;
+use clap::builder::BoolishValueParser;
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use std::path::PathBuf;
+
+/// A single executable linux container.
+///
+/// Options for snow itself come first, everything after `--` is the command to
+/// run in the container instead of the image Cmd. Every option can also be set
+/// through the `SNOW_*` environment variable shown next to it. Options given
+/// more than once take a list there, separated by commas, or by newlines for
+/// --volume, --device, --env and --env-file whose values may hold commas.
+#[derive(Debug, Parser)]
+#[command(
+    name = "snow",
+    version,
+    args_conflicts_with_subcommands = true,
+    subcommand_value_name = "TOOL",
+    subcommand_help_heading = "Tools"
+)]
+pub struct Cli {
+    #[command(subcommand)]
+    pub tool: Option<Tool>,
+
+    #[command(flatten)]
+    pub run: RunOptions,
+}
+
+#[derive(Debug, Args)]
+pub struct RunOptions {
+    /// Log filter in env_logger syntax, e.g. `info` or `snow=debug`, defaults to RUST_LOG.
+    #[arg(long, env = "SNOW_LOG")]
+    pub log: Option<String>,
+
+    /// Don't check the image against the digest recorded when it was packed.
+    #[arg(long, env = "SNOW_SKIP_DIGEST_CHECK", value_parser = BoolishValueParser::new())]
+    pub skip_digest_check: bool,
+
+    /// Mount the image directly even if it was packed with a dm-verity hash tree.
+    #[arg(long, env = "SNOW_SKIP_VERITY", value_parser = BoolishValueParser::new())]
+    pub skip_verity: bool,
+
+    /// Run without root privileges inside a user namespace, the default when
+    /// not started as root.
+    #[arg(long, env = "SNOW_ROOTLESS", value_parser = BoolishValueParser::new())]
+    pub rootless: bool,
+
+    /// How rootless mode maps ids into the user namespace.
+    #[arg(long, env = "SNOW_ID_MAP", value_enum, default_value_t = IdMap::Auto)]
+    pub id_map: IdMap,
+
+    /// Give the container its own PID namespace with snow as its init, or
+    /// share the host's.
+    #[arg(long, env = "SNOW_PID", value_enum, default_value_t = PidMode::Private)]
+    pub pid: PidMode,
+
+    /// Network the container is attached to.
+    #[arg(long, env = "SNOW_NETWORK", value_enum, default_value_t = NetworkMode::Host)]
+    pub network: NetworkMode,
+
+    /// Publish a container port on the host.
+    #[arg(
+        short,
+        long,
+        env = "SNOW_PUBLISH",
+        value_delimiter = ',',
+        value_name = "HOSTPORT:CONTAINERPORT[/udp]"
+    )]
+    pub publish: Vec<PortMapping>,
+
+    /// Bind a host file or directory into the container, read-only with `ro`.
+    /// Host mounts below it show up in the container with rslave.
+    #[arg(
+        short,
+        long,
+        env = "SNOW_VOLUME",
+        value_delimiter = '\n',
+        value_name = "HOSTPATH:CONTAINERPATH[:ro|rw][,rslave|rprivate]"
+    )]
+    pub volume: Vec<Volume>,
+
+    /// Keep the container's changes to the image in this host directory, to
+    /// pick them up again on the next run of the same image.
+    #[arg(long, env = "SNOW_PERSIST", value_name = "DIR")]
+    pub persist: Option<PathBuf>,
+
+    /// Hostname of the container, defaults to the name of the executable.
+    #[arg(long, env = "SNOW_HOSTNAME")]
+    pub hostname: Option<String>,
+
+    /// Parent of the container's cgroup, relative to the cgroup2 root.
+    /// Defaults to snow.slice, or next to snow's own cgroup when rootless.
+    #[arg(long, env = "SNOW_CGROUP_PARENT", value_name = "PATH")]
+    pub cgroup_parent: Option<PathBuf>,
+
+    /// Memory limit in bytes, with an optional k, m or g suffix.
+    #[arg(long, env = "SNOW_MEMORY", value_name = "SIZE", value_parser = cgroup::parse_size)]
+    pub memory: Option<u64>,
+
+    /// How many CPUs worth of time the container may use, e.g. 1.5.
+    #[arg(long, env = "SNOW_CPUS", value_parser = cgroup::parse_cpus)]
+    pub cpus: Option<f64>,
+
+    /// Maximum number of processes in the container.
+    #[arg(long, env = "SNOW_PIDS_LIMIT")]
+    pub pids_limit: Option<u64>,
+
+    /// IO weight relative to other cgroups, from 1 to 10000 with 100 the default.
+    #[arg(long, env = "SNOW_IO_WEIGHT", value_parser = clap::value_parser!(u16).range(1..=10000))]
+    pub io_weight: Option<u16>,
+
+    /// Where the container's /dev comes from.
+    #[arg(long, env = "SNOW_DEV", value_enum, default_value_t = DevMode::Private)]
+    pub dev: DevMode,
+
+    /// Pass a host device through to the container's private /dev.
+    #[arg(
+        long,
+        env = "SNOW_DEVICE",
+        value_delimiter = '\n',
+        value_name = "HOSTPATH[:CONTAINERPATH]"
+    )]
+    pub device: Vec<DeviceMapping>,
+
+    /// Give the container every capability, no seccomp filter and the host
+    /// kernel's interfaces in /proc and /sys, like `docker run --privileged`.
+    #[arg(long, env = "SNOW_PRIVILEGED", value_parser = BoolishValueParser::new())]
+    pub privileged: bool,
+
+    /// Grant the container a capability on top of Docker's default set, e.g.
+    /// NET_ADMIN, or ALL.
+    #[arg(
+        long,
+        env = "SNOW_CAP_ADD",
+        value_delimiter = ',',
+        value_name = "CAPABILITY"
+    )]
+    pub cap_add: Vec<String>,
+
+    /// Take a capability away from the container, or ALL.
+    #[arg(
+        long,
+        env = "SNOW_CAP_DROP",
+        value_delimiter = ',',
+        value_name = "CAPABILITY"
+    )]
+    pub cap_drop: Vec<String>,
+
+    /// Seccomp profile in Docker's JSON format, or `unconfined` to run without
+    /// one. Defaults to Docker's default profile.
+    #[arg(long, env = "SNOW_SECCOMP", value_name = "unconfined|FILE")]
+    pub seccomp: Option<String>,
+
+    /// User the command runs as, overriding the image User. Names are looked up
+    /// in the image's /etc/passwd and /etc/group.
+    #[arg(short, long, env = "SNOW_USER", value_name = "NAME|UID[:GROUP|GID]")]
+    pub user: Option<String>,
+
+    /// Keep the host root filesystem reachable inside the container at PATH,
+    /// /mnt/root if not given. It is detached by default.
+    #[arg(
+        long,
+        env = "SNOW_KEEP_HOST_ROOT",
+        value_name = "PATH",
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = "/mnt/root"
+    )]
+    pub keep_host_root: Option<PathBuf>,
+
+    /// Public key file the image signature is checked against, in addition to
+    /// the key compiled into the runtime.
+    #[arg(long, env = "SNOW_TRUSTED_KEY_FILE", value_name = "FILE")]
+    pub trusted_key: Option<PathBuf>,
+
+    /// Set a variable in the container with `NAME=value`, or pass `NAME` through
+    /// from the host. A value can't span lines, a newline starts the next variable.
+    #[arg(
+        short,
+        long,
+        env = "SNOW_ENV",
+        value_delimiter = '\n',
+        value_name = "NAME[=VALUE]"
+    )]
+    pub env: Vec<String>,
+
+    /// Read variables for the container from a file of `NAME=value` or `NAME` lines.
+    #[arg(
+        long,
+        env = "SNOW_ENV_FILE",
+        value_delimiter = '\n',
+        value_name = "FILE"
+    )]
+    pub env_file: Vec<PathBuf>,
+
+    /// Command to run in the container, the image Entrypoint is prepended to it.
+    #[arg(last = true, value_name = "COMMAND")]
+    pub command: Vec<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum PidMode {
+    Private,
+    Host,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Tool {
+    /// Stamp a squashfs image into a copy of a snow runtime.
+    Pack(PackOptions),
+
+    /// Merge the changes a container kept with --persist into the image,
+    /// making a new snow executable of it.
+    Commit(CommitOptions),
+
+    /// Generate a key pair for signing images.
+    Keygen {
+        /// Where to write the hex encoded private key.
+        secret_key: PathBuf,
+        /// Where to write the hex encoded public key.
+        public_key: PathBuf,
+    },
+}
+
+#[derive(Debug, Args)]
+pub struct PackOptions {
+    /// Squashfs image used as the container rootfs.
+    pub image: PathBuf,
+
+    /// Where to write the packed executable.
+    pub out: PathBuf,
+
+    /// Runtime to stamp, any image it already carries is replaced.
+    #[arg(long, default_value = "/proc/self/exe")]
+    pub runtime: PathBuf,
+
+    /// Image config as printed by `docker inspect --format '{{json .Config}}'`.
+    #[arg(long, value_name = "FILE")]
+    pub config: Option<PathBuf>,
+
+    /// Sign the image with this private key.
+    #[arg(long, value_name = "FILE")]
+    pub signing_key: Option<PathBuf>,
+
+    /// Append a dm-verity hash tree so the image is checked as it is read.
+    #[arg(long)]
+    pub verity: bool,
+}
+
+#[derive(Debug, Args)]
+pub struct CommitOptions {
+    /// Directory the container kept its changes in with --persist.
+    #[arg(long, value_name = "DIR")]
+    pub persist: PathBuf,
+
+    /// Where to write the new executable.
+    #[arg(long, value_name = "FILE")]
+    pub out: PathBuf,
+
+    /// Read the changes inside a user namespace, the default when not started
+    /// as root. Has to match how the container ran.
+    #[arg(long)]
+    pub rootless: bool,
+
+    /// How rootless mode maps ids into the user namespace.
+    #[arg(long, value_enum, default_value_t = IdMap::Auto)]
+    pub id_map: IdMap,
+
+    /// Public key file the current image signature is checked against, in
+    /// addition to the key compiled into the runtime.
+    #[arg(long, env = "SNOW_TRUSTED_KEY_FILE", value_name = "FILE")]
+    pub trusted_key: Option<PathBuf>,
+
+    /// Sign the new image with this private key.
+    #[arg(long, value_name = "FILE")]
+    pub signing_key: Option<PathBuf>,
+
+    /// Append a dm-verity hash tree so the image is checked as it is read.
+    #[arg(long)]
+    pub verity: bool,
+}
@@ -0,0 +1,117 @@
+use crate::cli::CommitOptions;
+use crate::{mount, pack, payload, persist, signature, userns};
+use anyhow::{bail, Context, Result};
+use log::info;
+use nix::sys::stat;
+use nix::unistd;
+use std::ffi::OsString;
+use std::fs::File;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+/// Builds the new image out of the overlay of the persisted upper layer on the
+/// image we carry. The overlay takes care of the whiteouts and opaque
+/// directories, what mksquashfs sees is the rootfs the container saw.
+fn build_image(
+    payload: &payload::Payload,
+    persist: &persist::PersistDir,
+    rootless: bool,
+    image: &Path,
+) -> Result<()> {
+    mount::enter_new_mount_ns(false)?;
+
+    // The same trick as running a container, our mounts go away with us. By
+    // pid, mksquashfs has its own /proc/self.
+    let useless_dir = PathBuf::from(format!("/proc/{}/fd", mount::host_pid()?));
+    mount::tmpfs(useless_dir.clone())?;
+    unistd::mkdir(&useless_dir.join("lower"), stat::Mode::S_IRWXU)?;
+    unistd::mkdir(&useless_dir.join("merged"), stat::Mode::S_IRWXU)?;
+
+    let squashfs_section = payload
+        .section(payload::SQUASHFS_SECTION)
+        .expect("squashfs section not found");
+    if rootless {
+        mount::mount_squashfs_with_squashfuse(squashfs_section.offset)?;
+    } else {
+        mount::mount_squashfs_with_loop_devices(
+            useless_dir.clone(),
+            squashfs_section.offset,
+            squashfs_section.size.next_multiple_of(512),
+            None,
+        )?;
+    }
+
+    let merged = useless_dir.join("merged");
+    info!(
+        "mounting {} over the image on {}",
+        persist.path().join("upper").display(),
+        merged.display()
+    );
+    mount::overlayfs_read_only(
+        &[persist.path().join("upper"), useless_dir.join("lower")],
+        &merged,
+        rootless,
+    )?;
+
+    if rootless {
+        userns::ensure_owners_mapped(&merged)?;
+    }
+
+    info!("building {} from {}", image.display(), merged.display());
+    let status = Command::new("mksquashfs")
+        .arg(&merged)
+        .arg(image)
+        .args(["-noappend", "-quiet"])
+        .status()
+        .context("failed running mksquashfs, is squashfs-tools installed?")?;
+    if !status.success() {
+        bail!("mksquashfs exited with {status}");
+    }
+
+    Ok(())
+}
+
+pub fn commit(options: &CommitOptions) -> Result<()> {
+    let mut self_exe = File::open("/proc/self/exe")?;
+
+    let Some(payload) = payload::Payload::locate(&mut self_exe)? else {
+        bail!("this snow runtime carries no container image to commit onto");
+    };
+    // Signing the new image vouches for the one it is built on.
+    signature::verify_image(
+        &payload,
+        &mut self_exe,
+        false,
+        options.trusted_key.as_deref(),
+    )?;
+    let Some(metadata) = payload.metadata(&mut self_exe)? else {
+        bail!("image has no recorded digest to tie --persist to, repack it");
+    };
+    // Holding it also keeps containers from changing it while we read it.
+    let persist = persist::PersistDir::open_existing(&options.persist, &metadata.squashfs_sha256)?;
+    let image_config = payload.read_section(&mut self_exe, payload::CONFIG_SECTION)?;
+
+    let rootless = options.rootless || !unistd::Uid::effective().is_root();
+    if rootless {
+        info!("entering new user ns");
+        userns::enter(options.id_map)?;
+    }
+
+    let mut image = OsString::from(&options.out);
+    image.push(".sqfs");
+    let image = PathBuf::from(image);
+
+    let result = build_image(&payload, &persist, rootless, &image).and_then(|()| {
+        pack::stamp(
+            &image,
+            image_config.as_deref(),
+            Path::new("/proc/self/exe"),
+            &options.out,
+            options.signing_key.as_deref(),
+            options.verity,
+        )
+    });
+    let _ = std::fs::remove_file(&image);
+
+    result
+}
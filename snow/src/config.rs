@@ -0,0 +1,102 @@
+use crate::env::DEFAULT_PATH;
+use anyhow::{bail, Context, Result};
+use nix::sys::signal::Signal;
+use nix::unistd::{access, AccessFlags};
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+
+/// The parts of the OCI image config (`docker inspect --format '{{json .Config}}'`)
+/// snow runs the container by. Docker writes missing values as `null`.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct ImageConfig {
+    #[serde(default)]
+    pub entrypoint: Option<Vec<String>>,
+    #[serde(default)]
+    pub cmd: Option<Vec<String>>,
+    #[serde(default)]
+    pub env: Option<Vec<String>>,
+    #[serde(default)]
+    pub working_dir: Option<String>,
+    #[serde(default)]
+    pub user: Option<String>,
+    #[serde(default)]
+    pub stop_signal: Option<String>,
+}
+
+impl ImageConfig {
+    /// What images packed without a config get, zsh in /root like snow always did.
+    pub fn legacy() -> Self {
+        ImageConfig {
+            entrypoint: Some(vec!["/bin/zsh".to_string()]),
+            working_dir: Some("/root".to_string()),
+            ..Default::default()
+        }
+    }
+
+    /// Entrypoint followed by `args`, or by Cmd when no arguments were given.
+    pub fn command(&self, args: &[String]) -> Result<Vec<String>> {
+        let mut command = self.entrypoint.clone().unwrap_or_default();
+
+        if args.is_empty() {
+            command.extend(self.cmd.clone().unwrap_or_default());
+        } else {
+            command.extend_from_slice(args);
+        }
+
+        if command.is_empty() {
+            bail!("the image has no Entrypoint or Cmd and no command was given");
+        }
+
+        Ok(command)
+    }
+
+    pub fn working_dir(&self) -> &str {
+        match self.working_dir.as_deref() {
+            Some("") | None => "/",
+            Some(working_dir) => working_dir,
+        }
+    }
+
+    pub fn user(&self) -> Option<&str> {
+        self.user.as_deref().filter(|user| !user.is_empty())
+    }
+
+    /// The signal `docker stop` would send, given as a name like `SIGQUIT` or
+    /// `QUIT` or as a number.
+    pub fn stop_signal(&self) -> Result<Signal> {
+        let stop_signal = match self.stop_signal.as_deref() {
+            Some("") | None => return Ok(Signal::SIGTERM),
+            Some(stop_signal) => stop_signal,
+        };
+
+        let signal = match stop_signal.parse::<i32>() {
+            Ok(number) => Signal::try_from(number),
+            Err(_) if stop_signal.starts_with("SIG") => stop_signal.parse(),
+            Err(_) => format!("SIG{stop_signal}").parse(),
+        };
+
+        signal.with_context(|| format!("invalid StopSignal {stop_signal}"))
+    }
+}
+
+/// Resolves `program` against the PATH in `env` like a shell would.
+pub fn find_program(program: &str, env: &[String]) -> Result<PathBuf> {
+    if program.contains('/') {
+        return Ok(PathBuf::from(program));
+    }
+
+    let path = env
+        .iter()
+        .find_map(|var| var.strip_prefix("PATH="))
+        .unwrap_or(DEFAULT_PATH);
+
+    for dir in path.split(':').filter(|dir| !dir.is_empty()) {
+        let candidate = Path::new(dir).join(program);
+        if candidate.is_file() && access(&candidate, AccessFlags::X_OK).is_ok() {
+            return Ok(candidate);
+        }
+    }
+
+    bail!("{program} was not found in PATH {path}");
+}
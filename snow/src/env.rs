@@ -0,0 +1,106 @@
+use anyhow::{Context, Result};
+use std::collections::BTreeMap;
+use std::path::Path;
+
+pub const DEFAULT_PATH: &str = "/usr/local/sbin:/usr/local/bin:/usr/sbin:/usr/bin:/sbin:/bin";
+
+/// Variables given on the command line, gathered on the host before we pivot
+/// away from it. Later ones win.
+pub fn host_overrides(
+    env_files: &[impl AsRef<Path>],
+    env: &[String],
+) -> Result<Vec<(String, String)>> {
+    let mut overrides = Vec::new();
+
+    for env_file in env_files {
+        let env_file = env_file.as_ref();
+        let content = std::fs::read_to_string(env_file)
+            .with_context(|| format!("failed reading env file {}", env_file.display()))?;
+
+        for line in content.lines() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            overrides.extend(parse_override(line));
+        }
+    }
+
+    for var in env {
+        overrides.extend(parse_override(var));
+    }
+
+    Ok(overrides)
+}
+
+/// `NAME=value` sets a variable, a bare `NAME` passes it through from the host
+/// and is ignored when the host doesn't have it, like `docker run --env`.
+fn parse_override(var: &str) -> Option<(String, String)> {
+    match var.split_once('=') {
+        Some((name, value)) => Some((name.to_string(), value.to_string())),
+        None => std::env::var(var)
+            .ok()
+            .map(|value| (var.to_string(), value)),
+    }
+}
+
+/// Parses `/etc/environment`, which holds `NAME=value` lines with optionally
+/// quoted values.
+fn parse_etc_environment(content: &str) -> Vec<(String, String)> {
+    content
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .filter_map(|line| line.strip_prefix("export ").unwrap_or(line).split_once('='))
+        .map(|(name, value)| {
+            let value = value.trim();
+            let value = value
+                .strip_prefix('"')
+                .and_then(|value| value.strip_suffix('"'))
+                .or_else(|| {
+                    value
+                        .strip_prefix('\'')
+                        .and_then(|value| value.strip_suffix('\''))
+                })
+                .unwrap_or(value);
+            (name.trim().to_string(), value.to_string())
+        })
+        .collect()
+}
+
+/// Builds the environment of the container process, must run inside the
+/// container root. From weakest to strongest: our defaults, with the user's
+/// `home` as HOME, the image `/etc/environment`, the image config Env and the
+/// command line overrides.
+pub fn container_environment(
+    home: &str,
+    image_env: &[String],
+    overrides: &[(String, String)],
+) -> Vec<String> {
+    let mut env = BTreeMap::new();
+
+    env.insert("PATH".to_string(), DEFAULT_PATH.to_string());
+    env.insert("HOME".to_string(), home.to_string());
+    for name in ["TERM", "LANG"] {
+        if let Ok(value) = std::env::var(name) {
+            env.insert(name.to_string(), value);
+        }
+    }
+
+    if let Ok(content) = std::fs::read_to_string("/etc/environment") {
+        env.extend(parse_etc_environment(&content));
+    }
+
+    env.extend(
+        image_env
+            .iter()
+            .filter_map(|var| var.split_once('='))
+            .map(|(name, value)| (name.to_string(), value.to_string())),
+    );
+
+    env.extend(overrides.iter().cloned());
+
+    env.into_iter()
+        .map(|(name, value)| format!("{name}={value}"))
+        .collect()
+}
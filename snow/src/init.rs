@@ -0,0 +1,111 @@
+use anyhow::Result;
+use log::debug;
+use nix::errno::Errno;
+use nix::sys::signal::{
+    kill, sigaction, SaFlags, SigAction, SigHandler, SigSet, SigmaskHow, Signal,
+};
+use nix::sys::wait::{waitpid, WaitPidFlag, WaitStatus};
+use nix::unistd::{self, Pid};
+
+/// Signals passed on to the container, SIGTERM becomes the image StopSignal.
+const FORWARDED_SIGNALS: [Signal; 4] = [
+    Signal::SIGTERM,
+    Signal::SIGINT,
+    Signal::SIGHUP,
+    Signal::SIGWINCH,
+];
+
+fn handled_signals() -> SigSet {
+    let mut signals = SigSet::empty();
+    for signal in FORWARDED_SIGNALS {
+        signals.add(signal);
+    }
+    signals.add(Signal::SIGCHLD);
+
+    signals
+}
+
+/// Blocks the signals we wait for so they queue up until `supervise` takes
+/// them, must happen before forking the process they are meant for.
+pub fn block_handled_signals() -> Result<()> {
+    handled_signals().thread_block()?;
+
+    Ok(())
+}
+
+/// Undoes `block_handled_signals`, the mask survives execve and the container
+/// process expects to get its signals.
+pub fn unblock_handled_signals() -> Result<()> {
+    nix::sys::signal::sigprocmask(SigmaskHow::SIG_UNBLOCK, Some(&handled_signals()), None)?;
+
+    Ok(())
+}
+
+/// Moves the calling process, the container command, into a process group of
+/// its own and makes that the foreground group of the terminal, like tini
+/// does. Ctrl-C or a resize then signals the container command alone rather
+/// than it and every snow in between, which would pass them on once more.
+pub fn isolate() -> Result<()> {
+    unistd::setpgid(Pid::from_raw(0), Pid::from_raw(0))?;
+
+    // Taking the terminal from the background raises SIGTTOU.
+    let ignore = SigAction::new(SigHandler::SigIgn, SaFlags::empty(), SigSet::empty());
+    // SAFETY: no handler is installed, only the disposition changes.
+    let previous = unsafe { sigaction(Signal::SIGTTOU, &ignore)? };
+    let result = unistd::tcsetpgrp(std::io::stdin(), unistd::getpgrp());
+    // SAFETY: as above, the disposition we had goes back.
+    unsafe { sigaction(Signal::SIGTTOU, &previous)? };
+
+    match result {
+        // No terminal, or not ours, nothing to take over.
+        Ok(()) | Err(Errno::ENOTTY) | Err(Errno::ENXIO) => Ok(()),
+        Err(err) => Err(err.into()),
+    }
+}
+
+/// The exit code a shell would report for `status`.
+fn exit_code(status: WaitStatus) -> Option<i32> {
+    match status {
+        WaitStatus::Exited(_, code) => Some(code),
+        WaitStatus::Signaled(_, signal, _) => Some(128 + signal as i32),
+        _ => None,
+    }
+}
+
+/// Forwards signals to `child` and reaps every child we have, which as PID 1
+/// includes whatever got orphaned in the container, until `child` exits.
+/// Returns the exit code to exit with.
+pub fn supervise(child: Pid, stop_signal: Signal) -> Result<i32> {
+    let signals = handled_signals();
+
+    loop {
+        match signals.wait()? {
+            Signal::SIGCHLD => loop {
+                let status = match waitpid(None, Some(WaitPidFlag::WNOHANG)) {
+                    Ok(WaitStatus::StillAlive) | Err(Errno::ECHILD) => break,
+                    Ok(status) => status,
+                    Err(err) => return Err(err.into()),
+                };
+
+                if status.pid() == Some(child) {
+                    if let Some(code) = exit_code(status) {
+                        debug!("{child} exited with {code}");
+                        return Ok(code);
+                    }
+                }
+            },
+            signal => {
+                let signal = match signal {
+                    Signal::SIGTERM => stop_signal,
+                    signal => signal,
+                };
+                debug!("forwarding {signal} to {child}");
+                match kill(child, signal) {
+                    // It exited already, we will get its SIGCHLD.
+                    Ok(()) | Err(Errno::ESRCH) => {}
+                    Err(err) => return Err(err.into()),
+                }
+            }
+        }
+    }
+}
@@ -1,125 +1,245 @@
-#![feature(const_intrinsic_copy)]
-#![feature(const_mut_refs)]
-
+mod capabilities;
+mod cgroup;
+mod cli;
+mod commit;
+mod config;
+mod env;
+mod init;
 mod mount;
-use anyhow::Result;
-use goblin::elf::Elf;
-use log::info;
-use loopdev::{LoopControl, LoopDevice};
-use nix::mount::{mount, MsFlags};
+mod netlink;
+mod network;
+mod pack;
+mod payload;
+mod persist;
+mod ports;
+mod seccomp;
+mod signature;
+mod slirp;
+mod user;
+mod userns;
+mod verity;
+mod volume;
+use anyhow::{bail, Result};
+use clap::Parser;
+use log::{info, warn};
+use nix::mount::{mount, umount2, MntFlags, MsFlags};
 use nix::sched::{unshare, CloneFlags};
+use nix::sys::prctl;
+use nix::sys::signal::Signal;
 use nix::sys::stat;
 use nix::unistd;
-use nix::unistd::execve;
 use nix::unistd::pivot_root;
-use rand::Rng;
-use std::ffi::{CStr, CString};
-use std::hint::black_box;
-use std::io::prelude::*;
-use std::path::PathBuf;
+use nix::unistd::{execve, ForkResult};
+use std::ffi::CString;
+use std::fs::File;
+use std::os::unix::ffi::OsStringExt;
+use std::path::{Path, PathBuf};
 use std::str::FromStr;
 
-const SQUASHFS_BYTES: &[u8] = include_bytes!("../../container/alpine-snow.squashfs");
-
-#[used]
-#[link_section = ".squashfs"]
-#[allow(long_running_const_eval)]
-pub static SQUASHFS_SECTION: [u8; SQUASHFS_BYTES.len()] = {
-    let mut bytes = [0u8; SQUASHFS_BYTES.len()];
-    unsafe {
-        std::ptr::copy_nonoverlapping(
-            SQUASHFS_BYTES.as_ptr(),
-            &mut bytes as *mut _,
-            SQUASHFS_BYTES.len(),
-        )
-    };
-    bytes
-};
-
-fn get_squashfs_section_address() -> Result<Option<u64>> {
-    let mut file = std::fs::File::open("/proc/self/exe")?;
-    let mut buffer = Vec::new();
-    file.read_to_end(&mut buffer)?;
-
-    let elf = Elf::parse(&buffer)?;
-
-    for section in elf.section_headers.iter() {
-        let name = elf
-            .shdr_strtab
-            .get_at(section.sh_name)
-            .expect("failed to get section name");
-        if name == ".squashfs" {
-            return Ok(Some(section.sh_addr));
-        }
+/// The binary name made into a valid hostname, so containers of different
+/// snow executables tell themselves apart.
+fn default_hostname() -> String {
+    let name = std::env::args_os()
+        .next()
+        .map(PathBuf::from)
+        .and_then(|path| {
+            path.file_stem()
+                .map(|name| name.to_string_lossy().into_owned())
+        })
+        .unwrap_or_default();
+
+    let hostname: String = name
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '-' })
+        .take(63)
+        .collect();
+    let hostname = hostname.trim_matches('-');
+
+    match hostname {
+        "" => "snow".to_string(),
+        hostname => hostname.to_string(),
     }
-
-    Ok(None)
 }
 
-fn enter_new_mount_ns() -> Result<()> {
-    unshare(CloneFlags::CLONE_NEWNS)?;
-    mount::<str, str, str, str>(None, "/", None, MsFlags::MS_PRIVATE | MsFlags::MS_REC, None)?;
+fn enter_new_uts_ns(hostname: &str) -> Result<()> {
+    unshare(CloneFlags::CLONE_NEWUTS)?;
+    unistd::sethostname(hostname)?;
 
     Ok(())
 }
 
-fn create_loop_device(target_file: PathBuf, offset: u64) -> Result<LoopDevice> {
-    let loop_control = LoopControl::open()?;
-    let loop_device = loop_control.next_free()?;
-
-    loop_device
-        .with()
-        .offset(offset)
-        .read_only(true)
-        .attach(target_file)?;
-
-    Ok(loop_device)
-}
-
-fn create_overlayfs_directories(target: PathBuf) -> Result<()> {
+/// Creates the overlay directories in `target`, upper and work only when they
+/// don't come from a persist dir.
+fn create_overlayfs_directories(target: PathBuf, persist: bool) -> Result<()> {
     unistd::mkdir(&target.join("lower"), stat::Mode::S_IRWXU)?;
-    unistd::mkdir(&target.join("work"), stat::Mode::S_IRWXU)?;
-    unistd::mkdir(&target.join("upper"), stat::Mode::S_IRWXU)?;
+    if !persist {
+        unistd::mkdir(&target.join("work"), stat::Mode::S_IRWXU)?;
+        // The container root takes its mode from upper, users other than root
+        // have to get through it too.
+        unistd::mkdir(&target.join("upper"), stat::Mode::from_bits_truncate(0o755))?;
+    }
     unistd::mkdir(&target.join("merged"), stat::Mode::S_IRWXU)?;
 
     Ok(())
 }
 
-fn pivot_rootfs_place_old_at_mnt_root(new_root: PathBuf) -> Result<()> {
-    let put_old = new_root.join("mnt/root");
-    unistd::mkdir(&put_old, stat::Mode::S_IRWXU)?;
+/// Makes `new_root` the root. The host's old root stays at `keep_host_root`
+/// inside it when given, otherwise it is detached and leaves no trace.
+fn pivot_rootfs(new_root: PathBuf, keep_host_root: Option<&Path>) -> Result<()> {
+    let put_old = match keep_host_root {
+        // Resolved in the image, whose symlinks could point us at the host.
+        Some(keep_host_root) => {
+            let put_old = volume::secure_join(&new_root, keep_host_root)?;
+            std::fs::create_dir_all(&put_old)?;
+            put_old
+        }
+        // A name of our own, the image or a persisted upper layer may have
+        // anything at a fixed one.
+        None => unistd::mkdtemp(&new_root.join(".snow-old-root-XXXXXX"))?,
+    };
 
     pivot_root(&new_root, &put_old)?;
 
-    unistd::chdir("/root")?;
+    unistd::chdir("/")?;
+
+    let old_root = Path::new("/").join(put_old.strip_prefix(&new_root)?);
+    match keep_host_root {
+        // It followed the host's mounts only for the volumes' sake.
+        Some(_) => mount::<str, Path, str, str>(
+            None,
+            &old_root,
+            None,
+            MsFlags::MS_PRIVATE | MsFlags::MS_REC,
+            None,
+        )?,
+        None => {
+            // Lazily, whatever we still use from the host, like the image
+            // mount under the overlay, stays with us.
+            umount2(&old_root, MntFlags::MNT_DETACH)?;
+            std::fs::remove_dir(&old_root)?;
+        }
+    }
 
     Ok(())
 }
 
-fn exec_zsh() -> Result<()> {
-    let sbin_init = CString::new("/bin/zsh")?;
-    let mut args_cstring: Vec<CString> = std::env::args()
-        .map(|arg| CString::new(arg).map_or(CString::default(), |res| res))
-        .collect::<Vec<CString>>();
-
-    let _ = std::mem::replace(&mut args_cstring[0], CString::new("zsh")?);
-
-    let mut args_cstr = Vec::<&CStr>::new();
+fn exec_entrypoint(
+    image_config: &config::ImageConfig,
+    args: &[String],
+    user: Option<&str>,
+    env_overrides: &[(String, String)],
+    capabilities: &caps::CapsHashSet,
+    seccomp_profile: Option<&seccomp::Profile>,
+    privileged: bool,
+) -> Result<()> {
+    let command = image_config.command(args)?;
+    let user = user.map(user::resolve).transpose()?;
+    let env = env::container_environment(
+        user.as_ref().map_or("/root", |user| &user.home),
+        image_config.env.as_deref().unwrap_or_default(),
+        env_overrides,
+    );
 
-    for arg_cstring in args_cstring.iter() {
-        args_cstr.push(arg_cstring.as_c_str())
+    // Docker creates a missing working directory as well.
+    let working_dir = image_config.working_dir();
+    std::fs::create_dir_all(working_dir)?;
+    unistd::chdir(working_dir)?;
+
+    if let Some(user) = &user {
+        info!(
+            "switching to uid {} gid {} groups {:?}",
+            user.uid, user.gid, user.groups
+        );
+        // The container's capabilities are applied after.
+        prctl::set_keepcaps(true)?;
+        user::switch(user)?;
     }
 
-    execve::<&CStr, &CStr>(sbin_init.as_c_str(), &args_cstr, &[])?;
+    let program = CString::new(
+        config::find_program(&command[0], &env)?
+            .into_os_string()
+            .into_vec(),
+    )?;
+    let args_cstring = command
+        .into_iter()
+        .map(CString::new)
+        .collect::<Result<Vec<CString>, _>>()?;
+    let env_cstring = env
+        .into_iter()
+        .map(CString::new)
+        .collect::<Result<Vec<CString>, _>>()?;
+
+    init::isolate()?;
+    init::unblock_handled_signals()?;
+    info!("limiting capabilities to {capabilities:?}");
+    capabilities::apply(capabilities)?;
+    // Privileged containers keep setuid programs like sudo and ping working.
+    if !privileged {
+        prctl::set_no_new_privs()?;
+    }
+    if let Some(seccomp_profile) = seccomp_profile {
+        info!("installing seccomp filter");
+        seccomp_profile.install()?;
+    }
+    execve::<CString, CString>(&program, &args_cstring, &env_cstring)?;
 
     Ok(())
 }
 
-fn main() -> Result<()> {
-    env_logger::init();
+fn run(options: &cli::RunOptions) -> Result<()> {
+    let mut self_exe = File::open("/proc/self/exe")?;
+
+    let Some(payload) = payload::Payload::locate(&mut self_exe)? else {
+        bail!("this snow runtime carries no container image, stamp one with `snow pack`");
+    };
+
+    signature::verify_image(
+        &payload,
+        &mut self_exe,
+        options.skip_digest_check,
+        options.trusted_key.as_deref(),
+    )?;
+
+    let image_config = match payload.read_section(&mut self_exe, payload::CONFIG_SECTION)? {
+        Some(image_config) => serde_json::from_slice(&image_config)?,
+        None => config::ImageConfig::legacy(),
+    };
+
+    let env_overrides = env::host_overrides(&options.env_file, &options.env)?;
+
+    let metadata = payload.metadata(&mut self_exe)?;
+    let persist = match &options.persist {
+        Some(path) => {
+            let Some(metadata) = &metadata else {
+                bail!("image has no recorded digest to tie --persist to, repack it");
+            };
+            info!("persisting changes in {}", path.display());
+            Some(persist::PersistDir::open(path, &metadata.squashfs_sha256)?)
+        }
+        None => None,
+    };
 
-    // prevents the squashfs section from being optimized out.
-    black_box(SQUASHFS_SECTION[rand::thread_rng().gen_range(0..SQUASHFS_SECTION.len())]);
+    let verity_metadata = metadata.and_then(|metadata| metadata.verity);
+    let verity = match verity_metadata {
+        Some(_) if options.skip_verity => {
+            warn!("not protecting the image with dm-verity");
+            None
+        }
+        Some(verity_metadata) => {
+            let Some(verity_section) = payload.section(payload::VERITY_SECTION).cloned() else {
+                bail!("image hash tree not found, the binary is probably corrupted");
+            };
+            Some((verity_metadata, verity_section))
+        }
+        None => None,
+    };
+
+    let rootless = options.rootless || !unistd::Uid::effective().is_root();
+    if rootless && verity.is_some() {
+        bail!(
+            "dm-verity is not available rootless, pass --skip-verity to mount the image directly"
+        );
+    }
 
     info!("pid: {}", std::process::id());
 
@@ -130,18 +250,100 @@ fn main() -> Result<()> {
     // will be automaticaly lazily unmounted when this process is reaped.
     let useless_dir = PathBuf::from_str("/proc/self/fd")?;
 
-    let squashfs_offset = get_squashfs_section_address()?.expect("squashfs section not found");
+    let squashfs_section = payload
+        .section(payload::SQUASHFS_SECTION)
+        .expect("squashfs section not found");
+    let squashfs_offset = squashfs_section.offset;
+    // The loop driver works in whole sectors and dm-verity in whole blocks, the
+    // padding after the image is zeroed by `snow pack`.
+    let squashfs_size_limit = match &verity {
+        Some((verity_metadata, _)) => verity_metadata.data_blocks * 4096,
+        None => squashfs_section.size.next_multiple_of(512),
+    };
 
-    info!("entering new mount ns");
-    enter_new_mount_ns()?;
+    let stop_signal = image_config.stop_signal()?;
+    // Read now, the profile may be a host file.
+    let (seccomp_profile, mut capabilities) = match options.privileged {
+        true => (None, caps::runtime::thread_all_supported()),
+        false => (
+            seccomp::load(options.seccomp.as_deref())?,
+            capabilities::resolve(&options.cap_add, &options.cap_drop)?,
+        ),
+    };
 
-    info!("creating loop device on self exe, squashfs offset {squashfs_offset}");
-    let loop_device = create_loop_device("/proc/self/exe".into(), squashfs_offset)?;
+    if rootless {
+        info!("entering new user ns");
+        userns::enter(options.id_map)?;
+    }
+
+    if rootless && options.network == network::NetworkMode::Bridge {
+        bail!("bridge networking needs root, use --network slirp instead");
+    }
+    let limits = cgroup::Limits {
+        memory: options.memory,
+        cpus: options.cpus,
+        pids: options.pids_limit,
+        io_weight: options.io_weight,
+    };
+    let cgroup_parent = match &options.cgroup_parent {
+        Some(cgroup_parent) => cgroup_parent.clone(),
+        None => cgroup::default_parent(rootless)?,
+    };
+    let cgroup = match cgroup::Cgroup::create(&cgroup_parent, &limits) {
+        Ok(cgroup) => Some(cgroup),
+        Err(err) if limits.is_empty() => {
+            warn!("running without a cgroup of its own: {err:#}");
+            None
+        }
+        Err(err) => return Err(err.context("failed creating the container cgroup")),
+    };
+
+    info!("setting up {:?} network", options.network);
+    let mut network = network::setup(options.network)?;
+    network.serve()?;
+    network.publish(&options.publish)?;
+
+    if options.pid == cli::PidMode::Private {
+        info!("entering new pid ns");
+        unshare(CloneFlags::CLONE_NEWPID)?;
+    }
 
-    let loop_device_path = loop_device
-        .path()
-        .expect("failed to get path of loop device!");
-    info!("using loop device {}", loop_device_path.display());
+    // We stay behind on the host to pass signals on and exit with the
+    // container's status, the child sets the container up.
+    init::block_handled_signals()?;
+    // SAFETY: we are still single threaded at this point.
+    if let ForkResult::Parent { child } = unsafe { unistd::fork()? } {
+        // With a pid ns of its own the child is the container init and turns
+        // SIGTERM into the stop signal itself, otherwise it becomes the
+        // workload.
+        let stop_signal = match options.pid {
+            cli::PidMode::Private => Signal::SIGTERM,
+            cli::PidMode::Host => stop_signal,
+        };
+        let code = init::supervise(child, stop_signal)?;
+        drop(network);
+        drop(cgroup);
+        drop(persist);
+        std::process::exit(code);
+    }
+
+    network.enter()?;
+    let cgroup = cgroup.map(cgroup::Cgroup::enter).transpose()?;
+    // The cgroup we are in becomes the root of the hierarchy the container
+    // sees once cgroup2 is mounted, along with the limits set on it.
+    info!("entering new cgroup ns");
+    unshare(CloneFlags::CLONE_NEWCGROUP)?;
+
+    let hostname = options.hostname.clone().unwrap_or_else(default_hostname);
+    info!("entering new uts ns with hostname {hostname}");
+    enter_new_uts_ns(&hostname)?;
+
+    let follow_host = options
+        .volume
+        .iter()
+        .any(|volume| volume.propagation != volume::Propagation::Private);
+    info!("entering new mount ns");
+    mount::enter_new_mount_ns(follow_host)?;
 
     info!("mounting tmpfs on {}", useless_dir.display());
     mount::tmpfs(useless_dir.clone())?;
@@ -150,16 +352,29 @@ fn main() -> Result<()> {
         "creating overlayfs directories on {}",
         useless_dir.display()
     );
-    create_overlayfs_directories(useless_dir.clone())?;
+    create_overlayfs_directories(useless_dir.clone(), persist.is_some())?;
+
+    if rootless {
+        mount::mount_squashfs_with_squashfuse(squashfs_offset)?;
+    } else {
+        mount::mount_squashfs_with_loop_devices(
+            useless_dir.clone(),
+            squashfs_offset,
+            squashfs_size_limit,
+            verity.as_ref(),
+        )?;
+    }
 
+    let layers_dir = match &persist {
+        Some(persist) => persist.path().to_path_buf(),
+        None => useless_dir.clone(),
+    };
     info!(
-        "mounting squashfs on {}",
-        useless_dir.join("lower").display()
+        "mounting overlayfs using {} and {}",
+        useless_dir.display(),
+        layers_dir.display()
     );
-    mount::squashfs(loop_device_path, useless_dir.join("lower"))?;
-
-    info!("mounting overlayfs using {}", useless_dir.display());
-    mount::overlayfs(useless_dir.clone())?;
+    mount::overlayfs(useless_dir.clone(), &layers_dir, rootless)?;
 
     let rootfs_dir = useless_dir.join("merged");
 
@@ -167,28 +382,115 @@ fn main() -> Result<()> {
         "mounting /proc /sys /dev /dev/pts on {}",
         rootfs_dir.display()
     );
-    mount::essential_system_filesystems(rootfs_dir.clone())?;
+    let devices = mount::essential_system_filesystems(
+        rootfs_dir.clone(),
+        rootless,
+        options.dev,
+        &options.device,
+    )?;
+
+    // None of them can be mounted from a user namespace.
+    if !rootless {
+        info!(
+            "mounting non essential system filesystems on {}",
+            rootfs_dir.display()
+        );
+        mount::non_essential_system_filesystems(rootfs_dir.clone(), options.privileged)?;
+    }
 
-    info!(
-        "mounting non essential system filesystems on {}",
-        rootfs_dir.display()
-    );
-    mount::non_essential_system_filesystems(rootfs_dir.clone())?;
+    if !options.privileged {
+        info!(
+            "masking kernel paths of /proc and /sys on {}",
+            rootfs_dir.display()
+        );
+        mount::mask_kernel_paths(&rootfs_dir)?;
+    }
 
     info!(
         "mounting network config files /etc/resolv.conf /etc/hostname /etc/hosts on {}",
         rootfs_dir.display()
     );
-    mount::network_configuration(rootfs_dir.clone())?;
+    network.write_configuration(useless_dir.join("etc"), &hostname)?;
+    mount::network_configuration(rootfs_dir.clone(), useless_dir.join("etc"))?;
+
+    if follow_host {
+        mount::<str, Path, str, str>(
+            None,
+            &rootfs_dir,
+            None,
+            MsFlags::MS_PRIVATE | MsFlags::MS_REC,
+            None,
+        )?;
+    }
+    volume::mount_volumes(&rootfs_dir, &options.volume)?;
+
+    match &options.keep_host_root {
+        Some(keep_host_root) => info!(
+            "pivoting rootfs to {}, placing old at {}",
+            rootfs_dir.display(),
+            keep_host_root.display()
+        ),
+        None => info!("pivoting rootfs to {}, detaching old", rootfs_dir.display()),
+    }
+    pivot_rootfs(rootfs_dir.clone(), options.keep_host_root.as_deref())?;
+
+    // Only now that we are done with the loop and dm-verity devices. In a user
+    // namespace the kernel already refuses the device nodes it makes.
+    if let Some(devices) = devices.filter(|_| !rootless && !options.privileged) {
+        match &cgroup {
+            Some(cgroup) => {
+                info!("limiting devices to {devices:?}");
+                cgroup::allow_only_devices(cgroup, &devices)?;
+            }
+            None => {
+                warn!("no cgroup to limit devices in, dropping CAP_MKNOD");
+                capabilities.remove(&caps::Capability::CAP_MKNOD);
+            }
+        }
+    }
+
+    if options.pid == cli::PidMode::Private {
+        // We are PID 1 of the container, the workload gets a process of its
+        // own while we reap after it.
+        // SAFETY: we are still single threaded at this point.
+        if let ForkResult::Parent { child } = unsafe { unistd::fork()? } {
+            std::process::exit(init::supervise(child, stop_signal)?);
+        }
+    }
 
     info!(
-        "pivoting rootfs to {}, placing old at /mnt/root",
-        rootfs_dir.display()
+        "exec-ing {:?} bye!",
+        image_config.command(&options.command)?
     );
-    pivot_rootfs_place_old_at_mnt_root(rootfs_dir.clone())?;
-
-    info!("exec-ing zsh bye!");
-    exec_zsh()?;
+    exec_entrypoint(
+        &image_config,
+        &options.command,
+        options.user.as_deref().or(image_config.user()),
+        &env_overrides,
+        &capabilities,
+        seccomp_profile.as_ref(),
+        options.privileged,
+    )?;
 
     Ok(())
 }
+
+fn main() -> Result<()> {
+    let cli = cli::Cli::parse();
+
+    let mut logger = env_logger::Builder::from_default_env();
+    if let Some(filter) = &cli.run.log {
+        logger.parse_filters(filter);
+    }
+    logger.init();
+
+    match &cli.tool {
+        Some(cli::Tool::Pack(options)) => pack::pack(options),
+        Some(cli::Tool::Commit(options)) => commit::commit(options),
+        Some(cli::Tool::Keygen {
+            secret_key,
+            public_key,
+        }) => signature::generate_keypair(secret_key, public_key),
+        None => run(&cli.run),
+    }
+}
@@ -1,8 +1,25 @@
-use anyhow::Result;
-use log::{debug, warn};
+use crate::{cgroup, payload, verity};
+use anyhow::{bail, Context, Result};
+use clap::ValueEnum;
+use log::{debug, info, warn};
+use loopdev::{LoopControl, LoopDevice};
+use nix::errno::Errno;
+use nix::libc;
 use nix::mount::{mount, MsFlags};
+use nix::sched::{unshare, CloneFlags};
+use nix::sys::prctl;
+use nix::sys::signal::Signal;
+use nix::sys::stat;
+use nix::sys::statvfs::{statvfs, FsFlags};
+use nix::unistd;
 use std::ffi::CString;
-use std::path::PathBuf;
+use std::fs::File;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::process::CommandExt;
+use std::path::{Component, Path, PathBuf};
+use std::process::Command;
+use std::str::FromStr;
+use std::time::{Duration, Instant};
 
 pub fn tmpfs(target: PathBuf) -> Result<()> {
     mount(
@@ -31,13 +48,61 @@ pub fn squashfs(loop_device_path: PathBuf, target: PathBuf) -> Result<()> {
     Ok(())
 }
 
-pub fn overlayfs(target: PathBuf) -> Result<()> {
-    let options = CString::new(format!(
+/// Mounts the squashfs at `offset` in `image` through squashfuse, for when we
+/// are in a user namespace where the kernel won't mount squashfs for us.
+///
+/// squashfuse stays in the foreground as our child and is killed when the
+/// container process exits, so it doesn't keep the mount namespace alive.
+pub fn squashfuse(image: &Path, offset: u64, target: PathBuf) -> Result<()> {
+    let mut squashfuse = Command::new("squashfuse");
+    squashfuse
+        .arg("-f")
+        .arg("-o")
+        .arg(format!("offset={offset}"))
+        .arg(image)
+        .arg(&target);
+
+    // SAFETY: prctl is async signal safe.
+    unsafe {
+        squashfuse.pre_exec(|| {
+            prctl::set_pdeathsig(Signal::SIGTERM)?;
+            Ok(())
+        });
+    }
+
+    let mut squashfuse = squashfuse
+        .spawn()
+        .context("failed running squashfuse, is it installed?")?;
+
+    let parent = target.parent().unwrap_or(Path::new("/"));
+    let deadline = Instant::now() + Duration::from_secs(10);
+    while stat::stat(&target)?.st_dev == stat::stat(parent)?.st_dev {
+        if let Some(status) = squashfuse.try_wait()? {
+            bail!("squashfuse exited with {status}");
+        }
+        if Instant::now() > deadline {
+            bail!("squashfuse did not mount {} in time", target.display());
+        }
+        std::thread::sleep(Duration::from_millis(10));
+    }
+
+    Ok(())
+}
+
+/// Mounts the overlay of `target`'s lower on `target`'s merged, with the upper
+/// and work directories found in `layers_dir`.
+pub fn overlayfs(target: PathBuf, layers_dir: &Path, rootless: bool) -> Result<()> {
+    let mut options = format!(
         "lowerdir={},upperdir={},workdir={},xino=off",
         target.join("lower").display(),
-        target.join("upper").display(),
-        target.join("work").display()
-    ))?;
+        layers_dir.join("upper").display(),
+        layers_dir.join("work").display()
+    );
+    // trusted.* xattrs are off limits in a user namespace.
+    if rootless {
+        options.push_str(",userxattr");
+    }
+    let options = CString::new(options)?;
 
     mount(
         Some("overlay"),
@@ -50,68 +115,331 @@ pub fn overlayfs(target: PathBuf) -> Result<()> {
     Ok(())
 }
 
-pub fn essential_system_filesystems(target: PathBuf) -> Result<()> {
-    mount::<str, PathBuf, str, str>(
-        Some("proc"),
-        &target.join("proc"),
-        Some("proc"),
-        MsFlags::empty(),
-        None,
-    )?;
+/// Mounts the overlay of `layers`, topmost first, read-only on `target`.
+/// Whiteouts and opaque directories in a layer hide what is below as usual.
+pub fn overlayfs_read_only(layers: &[PathBuf], target: &Path, rootless: bool) -> Result<()> {
+    let layers: Vec<_> = layers
+        .iter()
+        .map(|layer| layer.display().to_string())
+        .collect();
+    let mut options = format!("lowerdir={},xino=off", layers.join(":"));
+    if rootless {
+        options.push_str(",userxattr");
+    }
+    let options = CString::new(options)?;
 
-    mount::<str, PathBuf, str, str>(
-        Some("sysfs"),
-        &target.join("sys"),
-        Some("sysfs"),
-        MsFlags::empty(),
-        None,
+    mount(
+        Some("overlay"),
+        target,
+        Some("overlay"),
+        MsFlags::MS_RDONLY,
+        Some(options.as_c_str()),
     )?;
 
-    mount::<str, PathBuf, str, str>(
-        Some("/dev"),
-        &target.join("dev"),
-        None,
-        // We bind mount but not recursively so things like devpts won't be
-        // shared with the original mount namespace.
-        MsFlags::MS_BIND,
-        None,
-    )?;
+    Ok(())
+}
+
+/// Remounts a bind mount read-only. In a user namespace the kernel refuses to
+/// clear flags of the mounts we inherited, so the ones set are kept.
+fn remount_bind_readonly(target: &Path) -> Result<()> {
+    let flags = statvfs(target)?.flags();
+    let mut ms_flags = MsFlags::MS_REMOUNT | MsFlags::MS_BIND | MsFlags::MS_RDONLY;
+
+    for (fs_flag, ms_flag) in [
+        (FsFlags::ST_NOSUID, MsFlags::MS_NOSUID),
+        (FsFlags::ST_NODEV, MsFlags::MS_NODEV),
+        (FsFlags::ST_NOEXEC, MsFlags::MS_NOEXEC),
+        (FsFlags::ST_NOATIME, MsFlags::MS_NOATIME),
+        (FsFlags::ST_NODIRATIME, MsFlags::MS_NODIRATIME),
+        (FsFlags::ST_RELATIME, MsFlags::MS_RELATIME),
+    ] {
+        if flags.contains(fs_flag) {
+            ms_flags |= ms_flag;
+        }
+    }
+
+    mount::<str, Path, str, str>(None, target, None, ms_flags, None)?;
 
-    mount::<str, PathBuf, str, str>(
-        Some("devpts"),
-        &target.join("dev/pts"),
-        Some("devpts"),
+    Ok(())
+}
+
+/// struct mount_attr of linux/mount.h.
+#[repr(C)]
+struct MountAttr {
+    attr_set: u64,
+    attr_clr: u64,
+    propagation: u64,
+    userns_fd: u64,
+}
+
+const MOUNT_ATTR_RDONLY: u64 = 0x1;
+
+/// Makes the mount at `target` and every mount below it read-only at once,
+/// where a remount only goes one mount deep.
+pub fn make_read_only_recursively(target: &Path) -> Result<()> {
+    let path = CString::new(target.as_os_str().as_bytes())?;
+    let attr = MountAttr {
+        attr_set: MOUNT_ATTR_RDONLY,
+        attr_clr: 0,
+        propagation: 0,
+        userns_fd: 0,
+    };
+
+    // SAFETY: the path and attributes outlive the call, which only reads them.
+    Errno::result(unsafe {
+        libc::syscall(
+            libc::SYS_mount_setattr,
+            libc::AT_FDCWD,
+            path.as_ptr(),
+            libc::AT_RECURSIVE,
+            &attr as *const MountAttr,
+            std::mem::size_of::<MountAttr>(),
+        )
+    })
+    .with_context(|| format!("failed making {} read-only", target.display()))?;
+
+    Ok(())
+}
+
+/// Mounts a fresh `fstype`, or when rootless and the kernel won't let us,
+/// recursively binds the host's one from `host_path`.
+fn mount_or_bind_host(
+    fstype: &str,
+    host_path: &str,
+    target: PathBuf,
+    rootless: bool,
+) -> Result<()> {
+    match mount::<str, PathBuf, str, str>(
+        Some(fstype),
+        &target,
+        Some(fstype),
         MsFlags::empty(),
         None,
-    )?;
+    ) {
+        Ok(()) => {}
+        Err(err) if rootless => {
+            debug!("failed mounting {fstype} ({err}), binding the host's {host_path}");
+            mount::<str, PathBuf, str, str>(
+                Some(host_path),
+                &target,
+                None,
+                MsFlags::MS_BIND | MsFlags::MS_REC,
+                None,
+            )?;
+        }
+        Err(err) => return Err(err.into()),
+    }
+
+    Ok(())
+}
+
+/// Where the container's /dev comes from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum DevMode {
+    /// A tmpfs with only the basic devices and those given with --device.
+    Private,
+    /// The host /dev with every device in it.
+    Host,
+}
+
+/// A host device passed through to a private /dev, `hostpath[:containerpath]`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DeviceMapping {
+    pub host_path: PathBuf,
+    pub container_path: PathBuf,
+}
+
+impl FromStr for DeviceMapping {
+    type Err = anyhow::Error;
+
+    fn from_str(mapping: &str) -> Result<Self> {
+        let (host_path, container_path) = mapping.split_once(':').unwrap_or((mapping, mapping));
+        // `..` would take the bind out of /dev, anywhere in the container.
+        if !container_path.starts_with("/dev/")
+            || Path::new(container_path)
+                .components()
+                .any(|component| component == Component::ParentDir)
+        {
+            bail!("devices go under /dev, not {container_path}");
+        }
+
+        Ok(DeviceMapping {
+            host_path: PathBuf::from(host_path),
+            container_path: PathBuf::from(container_path),
+        })
+    }
+}
+
+/// Devices every container gets in a private /dev.
+const BASIC_DEVICES: [&str; 6] = ["null", "zero", "full", "random", "urandom", "tty"];
+
+/// Majors of the terminals devpts hands out.
+const PTY_MAJORS: std::ops::Range<u32> = 136..144;
+
+const DEV_SYMLINKS: [(&str, &str); 5] = [
+    ("/proc/self/fd", "fd"),
+    ("/proc/self/fd/0", "stdin"),
+    ("/proc/self/fd/1", "stdout"),
+    ("/proc/self/fd/2", "stderr"),
+    ("pts/ptmx", "ptmx"),
+];
+
+/// Binds the host device `source` onto a new file at `target`. Unlike
+/// creating the node, that works in a user namespace too.
+fn bind_device(source: &Path, target: &Path) -> Result<()> {
+    if let Some(parent) = target.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    File::create(target)?;
+    mount::<Path, Path, str, str>(Some(source), target, None, MsFlags::MS_BIND, None)
+        .with_context(|| format!("failed binding device {}", source.display()))?;
 
     Ok(())
 }
 
-pub fn non_essential_system_filesystems(target: PathBuf) -> Result<()> {
-    let fstypes_and_mountpoints: Vec<(&str, PathBuf)> = vec![
+/// Mounts a tmpfs on `dev` with the basic devices, `devices` and our terminal
+/// as the console. Returns the devices bound.
+fn private_dev(dev: &Path, devices: &[DeviceMapping]) -> Result<Vec<cgroup::Device>> {
+    mount::<str, Path, str, str>(
+        Some("tmpfs"),
+        dev,
+        Some("tmpfs"),
+        MsFlags::MS_NOSUID | MsFlags::MS_STRICTATIME,
+        Some("mode=755,size=65536k"),
+    )?;
+
+    let mut bound = vec![];
+    for device in BASIC_DEVICES {
+        let source = Path::new("/dev").join(device);
+        bind_device(&source, &dev.join(device))?;
+        bound.extend(cgroup::Device::of(&source)?);
+    }
+    if unistd::isatty(0)? {
+        // Our /proc/self/fd is covered by the tmpfs we work in. The terminal
+        // is bound by path, its open file belongs to the host mount namespace.
+        let terminal = std::fs::read_link("/proc/thread-self/fd/0")?;
+        bind_device(&terminal, &dev.join("console"))?;
+        bound.extend(cgroup::Device::of(&terminal)?);
+    }
+    for device in devices {
+        debug!(
+            "passing device {} through as {}",
+            device.host_path.display(),
+            device.container_path.display()
+        );
+        let container_path = device.container_path.strip_prefix("/dev")?;
+        bind_device(&device.host_path, &dev.join(container_path))?;
+        bound.extend(cgroup::Device::of(&device.host_path)?);
+    }
+
+    for (original, link) in DEV_SYMLINKS {
+        std::os::unix::fs::symlink(original, dev.join(link))?;
+    }
+    for directory in ["pts", "shm", "mqueue", "hugepages"] {
+        std::fs::create_dir_all(dev.join(directory))?;
+    }
+
+    Ok(bound)
+}
+
+/// Mounts /proc, /sys and /dev on `target`. Returns the devices in a private
+/// /dev, None for the host's which has them all.
+pub fn essential_system_filesystems(
+    target: PathBuf,
+    rootless: bool,
+    dev_mode: DevMode,
+    devices: &[DeviceMapping],
+) -> Result<Option<Vec<cgroup::Device>>> {
+    mount_or_bind_host("proc", "/proc", target.join("proc"), rootless)?;
+    mount_or_bind_host("sysfs", "/sys", target.join("sys"), rootless)?;
+
+    let devices = match dev_mode {
+        DevMode::Private => {
+            let mut devices = private_dev(&target.join("dev"), devices)?;
+            mount::<str, PathBuf, str, str>(
+                Some("devpts"),
+                &target.join("dev/pts"),
+                Some("devpts"),
+                MsFlags::MS_NOSUID | MsFlags::MS_NOEXEC,
+                Some("newinstance,ptmxmode=0666,mode=0620"),
+            )?;
+            devices.extend(cgroup::Device::of(&target.join("dev/pts/ptmx"))?);
+            // The terminals it hands out come and go.
+            devices.extend(PTY_MAJORS.map(|major| cgroup::Device {
+                kind: cgroup::DeviceKind::Char,
+                major,
+                minor: None,
+            }));
+
+            Some(devices)
+        }
+        DevMode::Host => {
+            if !devices.is_empty() {
+                warn!("the host /dev has every device already, ignoring --device");
+            }
+
+            // We bind mount but not recursively so things like devpts won't be
+            // shared with the original mount namespace. In a user namespace the
+            // kernel won't let us leave the mounts under /dev out, so devpts gets
+            // covered by our own instance instead.
+            let dev_flags = match rootless {
+                true => MsFlags::MS_BIND | MsFlags::MS_REC,
+                false => MsFlags::MS_BIND,
+            };
+            mount::<str, PathBuf, str, str>(
+                Some("/dev"),
+                &target.join("dev"),
+                None,
+                dev_flags,
+                None,
+            )?;
+
+            mount::<str, PathBuf, str, str>(
+                Some("devpts"),
+                &target.join("dev/pts"),
+                Some("devpts"),
+                MsFlags::empty(),
+                None,
+            )?;
+
+            None
+        }
+    };
+
+    Ok(devices)
+}
+
+/// Mounts the rest of the system filesystems a container may want. Those that
+/// let it meddle with the host kernel, like bpf or efivarfs, only when
+/// `privileged`.
+pub fn non_essential_system_filesystems(target: PathBuf, privileged: bool) -> Result<()> {
+    let mut fstypes_and_mountpoints: Vec<(&str, PathBuf)> = vec![
         ("mqueue", target.join("dev/mqueue")),
         // For some reason docker mounts it under the source name "cgroup",
         // but ubuntu calls it "cgroup2" so we go that way.
         ("cgroup2", target.join("sys/fs/cgroup")),
-        ("bpf", target.join("sys/fs/bpf")),
-        ("configfs", target.join("sys/kernel/config")),
-        ("tracefs", target.join("sys/kernel/tracing")),
-        ("efivarfs", target.join("sys/firmware/efi/efivars")),
-        ("securityfs", target.join("sys/kernel/security")),
-        ("pstore", target.join("sys/fs/pstore")),
-        ("hugetlbfs", target.join("dev/hugepages")),
-        ("binfmt_misc", target.join("proc/sys/fs/binfmt_misc")),
-        ("fusectl", target.join("sys/fs/fuse/connections")),
-        ("debugfs", target.join("sys/kernel/debug")),
         // Apparantly glibc expects /dev/shm to be a tmpfs but we are based on Alpine
         // so no need for that.
     ];
+    if privileged {
+        fstypes_and_mountpoints.extend([
+            ("bpf", target.join("sys/fs/bpf")),
+            ("configfs", target.join("sys/kernel/config")),
+            ("tracefs", target.join("sys/kernel/tracing")),
+            ("efivarfs", target.join("sys/firmware/efi/efivars")),
+            ("securityfs", target.join("sys/kernel/security")),
+            ("pstore", target.join("sys/fs/pstore")),
+            ("hugetlbfs", target.join("dev/hugepages")),
+            ("binfmt_misc", target.join("proc/sys/fs/binfmt_misc")),
+            ("fusectl", target.join("sys/fs/fuse/connections")),
+            ("debugfs", target.join("sys/kernel/debug")),
+        ]);
+    }
 
     for (fstype, mountpoint) in fstypes_and_mountpoints.iter() {
         match mount::<str, PathBuf, str, str>(
             Some(fstype),
-            &mountpoint,
+            mountpoint,
             Some(fstype),
             MsFlags::empty(),
             None,
@@ -133,26 +461,222 @@ pub fn non_essential_system_filesystems(target: PathBuf) -> Result<()> {
     Ok(())
 }
 
-pub fn network_configuration(target: PathBuf) -> Result<()> {
-    let network_configuration_files: [&str; 3] = ["etc/resolv.conf", "etc/hostname", "etc/hosts"];
+/// Kernel interfaces Docker hides from containers, /dev/null is bound over
+/// files and an empty tmpfs over directories.
+const MASKED_PATHS: [&str; 13] = [
+    "proc/acpi",
+    "proc/asound",
+    "proc/interrupts",
+    "proc/kcore",
+    "proc/keys",
+    "proc/latency_stats",
+    "proc/sched_debug",
+    "proc/scsi",
+    "proc/sysrq-trigger",
+    "proc/timer_list",
+    "proc/timer_stats",
+    "sys/devices/virtual/powercap",
+    "sys/firmware",
+];
 
-    for file in network_configuration_files {
+/// Kernel interfaces Docker leaves readable but not writable.
+const READONLY_PATHS: [&str; 5] = ["proc/bus", "proc/fs", "proc/irq", "proc/sys", "sys"];
+
+/// Hides and locks the parts of the /proc and /sys mounted on `target` that
+/// reach past the container into the host kernel. Paths the kernel doesn't
+/// have are skipped.
+pub fn mask_kernel_paths(target: &Path) -> Result<()> {
+    for path in MASKED_PATHS {
+        let path = target.join(path);
+        let result = match std::fs::symlink_metadata(&path) {
+            Ok(metadata) if metadata.is_dir() => mount::<str, PathBuf, str, str>(
+                Some("tmpfs"),
+                &path,
+                Some("tmpfs"),
+                MsFlags::MS_RDONLY,
+                None,
+            ),
+            Ok(_) => mount::<str, PathBuf, str, str>(
+                Some("/dev/null"),
+                &path,
+                None,
+                MsFlags::MS_BIND,
+                None,
+            ),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => continue,
+            Err(err) => return Err(err.into()),
+        };
+        result.with_context(|| format!("failed masking {}", path.display()))?;
+    }
+
+    // Binding each onto itself gives it a mount of its own to make read-only,
+    // recursively so what is mounted below stays.
+    for path in READONLY_PATHS {
+        let path = target.join(path);
+        if !path.exists() {
+            continue;
+        }
         mount::<PathBuf, PathBuf, str, str>(
-            Some(&PathBuf::from("/").join(file)),
-            &target.join(file),
+            Some(&path),
+            &path,
             None,
-            MsFlags::MS_BIND,
+            MsFlags::MS_BIND | MsFlags::MS_REC,
             None,
-        )?;
+        )
+        .with_context(|| format!("failed binding {}", path.display()))?;
+        make_read_only_recursively(&path)
+            .with_context(|| format!("failed making {} read-only", path.display()))?;
+    }
 
-        mount::<str, PathBuf, str, str>(
-            None,
+    Ok(())
+}
+
+/// Binds the hostname, hosts and resolv.conf files generated in
+/// `generated_dir` read-only over the image's.
+pub fn network_configuration(target: PathBuf, generated_dir: PathBuf) -> Result<()> {
+    let network_configuration_files: [(PathBuf, &str); 3] = [
+        (generated_dir.join("resolv.conf"), "etc/resolv.conf"),
+        (generated_dir.join("hostname"), "etc/hostname"),
+        (generated_dir.join("hosts"), "etc/hosts"),
+    ];
+
+    for (source, file) in network_configuration_files {
+        mount::<PathBuf, PathBuf, str, str>(
+            Some(&source),
             &target.join(file),
             None,
-            MsFlags::MS_REMOUNT | MsFlags::MS_BIND | MsFlags::MS_RDONLY,
+            MsFlags::MS_BIND,
             None,
         )?;
+
+        remount_bind_readonly(&target.join(file))?;
     }
 
     Ok(())
 }
+
+/// Enters a mount ns whose mounts stay ours. With `follow_host` those the host
+/// makes later still reach our copies, for the volumes that ask for it to
+/// keep, the rest is to be made private once they are bound.
+pub fn enter_new_mount_ns(follow_host: bool) -> Result<()> {
+    unshare(CloneFlags::CLONE_NEWNS)?;
+    let propagation = match follow_host {
+        true => MsFlags::MS_SLAVE,
+        false => MsFlags::MS_PRIVATE,
+    };
+    mount::<str, str, str, str>(None, "/", None, propagation | MsFlags::MS_REC, None)?;
+
+    Ok(())
+}
+
+/// Our pid as the host /proc knows it, `std::process::id` is the one in our
+/// PID namespace.
+pub fn host_pid() -> Result<String> {
+    Ok(std::fs::read_link("/proc/self")?
+        .to_string_lossy()
+        .into_owned())
+}
+
+/// Attaches a loop device over part of `target_file`. It detaches itself once
+/// the last user lets go, the mount or dm-verity device on it when there is
+/// one, or we when failing before that.
+fn create_loop_device(target_file: PathBuf, offset: u64, size_limit: u64) -> Result<LoopDevice> {
+    let loop_control = LoopControl::open()?;
+    let loop_device = loop_control.next_free()?;
+
+    loop_device
+        .with()
+        .offset(offset)
+        .size_limit(size_limit)
+        .read_only(true)
+        .autoclear(true)
+        .attach(target_file)?;
+
+    Ok(loop_device)
+}
+
+pub fn mount_squashfs_with_loop_devices(
+    useless_dir: PathBuf,
+    squashfs_offset: u64,
+    squashfs_size_limit: u64,
+    verity: Option<&(verity::VerityMetadata, payload::Section)>,
+) -> Result<()> {
+    info!(
+        "creating loop device on self exe, squashfs offset {squashfs_offset} size {squashfs_size_limit}"
+    );
+    let loop_device = create_loop_device(
+        "/proc/self/exe".into(),
+        squashfs_offset,
+        squashfs_size_limit,
+    )?;
+
+    let loop_device_path = loop_device
+        .path()
+        .expect("failed to get path of loop device!");
+    info!("using loop device {}", loop_device_path.display());
+
+    let verity_name = format!("snow-{}", host_pid()?);
+    // Held until the squashfs is mounted, the device then goes away on its
+    // own once it is unmounted.
+    let mut verity_device = None;
+    let squashfs_device_path = match verity {
+        Some((verity_metadata, verity_section)) => {
+            info!(
+                "creating loop device on self exe, verity offset {} size {}",
+                verity_section.offset, verity_section.size
+            );
+            let hash_loop_device = create_loop_device(
+                "/proc/self/exe".into(),
+                verity_section.offset,
+                verity_section.size,
+            )?;
+            let hash_loop_device_path = hash_loop_device
+                .path()
+                .expect("failed to get path of loop device!");
+
+            let verity_device_path = useless_dir.join("verity");
+            info!(
+                "creating dm-verity device {verity_name} over {} and {}",
+                loop_device_path.display(),
+                hash_loop_device_path.display()
+            );
+            verity_device = Some(verity::setup(
+                &verity_name,
+                &loop_device_path,
+                &hash_loop_device_path,
+                verity_metadata,
+                &verity_device_path,
+            )?);
+
+            verity_device_path
+        }
+        None => loop_device_path,
+    };
+
+    info!(
+        "mounting squashfs on {}",
+        useless_dir.join("lower").display()
+    );
+    squashfs(squashfs_device_path, useless_dir.join("lower"))?;
+    drop(verity_device);
+
+    Ok(())
+}
+
+/// Mounts the image on lower in our /proc/self/fd without root. The kernel
+/// doesn't mount squashfs in a user namespace so loop devices would be no use
+/// to us.
+pub fn mount_squashfs_with_squashfuse(squashfs_offset: u64) -> Result<()> {
+    // squashfuse has its own idea of /proc/self so it gets our exe and the
+    // mountpoint by pid.
+    let self_proc_dir = PathBuf::from(format!("/proc/{}", host_pid()?));
+    info!(
+        "mounting squashfs on {} with squashfuse, offset {squashfs_offset}",
+        self_proc_dir.join("fd/lower").display()
+    );
+    squashfuse(
+        &self_proc_dir.join("exe"),
+        squashfs_offset,
+        self_proc_dir.join("fd/lower"),
+    )
+}
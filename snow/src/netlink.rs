@@ -0,0 +1,257 @@
+use nix::errno::Errno;
+use nix::sys::socket::{
+    bind, recv, send, socket, AddressFamily, MsgFlags, NetlinkAddr, SockFlag, SockProtocol,
+    SockType,
+};
+use std::net::Ipv4Addr;
+use std::os::fd::{AsRawFd, BorrowedFd, OwnedFd};
+
+// Just enough of rtnetlink to wire up a container, see rtnetlink(7).
+const NLMSG_ERROR: u16 = 2;
+const RTM_NEWLINK: u16 = 16;
+const RTM_NEWADDR: u16 = 20;
+const RTM_NEWROUTE: u16 = 24;
+
+const NLM_F_REQUEST: u16 = 0x1;
+const NLM_F_ACK: u16 = 0x4;
+const NLM_F_EXCL: u16 = 0x200;
+const NLM_F_CREATE: u16 = 0x400;
+
+const IFLA_IFNAME: u16 = 3;
+const IFLA_MASTER: u16 = 10;
+const IFLA_LINKINFO: u16 = 18;
+const IFLA_NET_NS_FD: u16 = 28;
+const IFLA_INFO_KIND: u16 = 1;
+const IFLA_INFO_DATA: u16 = 2;
+const VETH_INFO_PEER: u16 = 1;
+const IFA_ADDRESS: u16 = 1;
+const IFA_LOCAL: u16 = 2;
+const RTA_GATEWAY: u16 = 5;
+
+const AF_UNSPEC: u8 = 0;
+const AF_INET: u8 = 2;
+const IFF_UP: u32 = 0x1;
+const RT_TABLE_MAIN: u8 = 254;
+const RTPROT_BOOT: u8 = 3;
+const RT_SCOPE_UNIVERSE: u8 = 0;
+const RTN_UNICAST: u8 = 1;
+
+const NLMSG_HEADER_SIZE: usize = 16;
+
+fn ifinfomsg(index: u32, flags: u32, change: u32) -> Vec<u8> {
+    let mut header = vec![AF_UNSPEC, 0, 0, 0];
+    header.extend_from_slice(&index.to_ne_bytes());
+    header.extend_from_slice(&flags.to_ne_bytes());
+    header.extend_from_slice(&change.to_ne_bytes());
+
+    header
+}
+
+/// A netlink request under construction, a nlmsghdr followed by the family
+/// header and attributes.
+struct Message {
+    buffer: Vec<u8>,
+}
+
+impl Message {
+    fn new(message_type: u16, flags: u16, header: &[u8]) -> Self {
+        let mut buffer = vec![0u8; NLMSG_HEADER_SIZE];
+        buffer[4..6].copy_from_slice(&message_type.to_ne_bytes());
+        buffer[6..8].copy_from_slice(&(flags | NLM_F_REQUEST | NLM_F_ACK).to_ne_bytes());
+        buffer.extend_from_slice(header);
+
+        Message { buffer }
+    }
+
+    fn align(&mut self) {
+        self.buffer.resize(self.buffer.len().next_multiple_of(4), 0);
+    }
+
+    fn attribute(&mut self, kind: u16, data: &[u8]) -> &mut Self {
+        self.align();
+        self.buffer
+            .extend_from_slice(&(4 + data.len() as u16).to_ne_bytes());
+        self.buffer.extend_from_slice(&kind.to_ne_bytes());
+        self.buffer.extend_from_slice(data);
+
+        self
+    }
+
+    /// An attribute holding whatever `build` adds to the message.
+    fn nested(&mut self, kind: u16, build: impl FnOnce(&mut Self)) -> &mut Self {
+        self.align();
+        let start = self.buffer.len();
+        self.attribute(kind, &[]);
+        build(self);
+
+        let length = (self.buffer.len() - start) as u16;
+        self.buffer[start..start + 2].copy_from_slice(&length.to_ne_bytes());
+
+        self
+    }
+
+    fn finish(mut self, sequence: u32) -> Vec<u8> {
+        self.align();
+        let length = self.buffer.len() as u32;
+        self.buffer[0..4].copy_from_slice(&length.to_ne_bytes());
+        self.buffer[8..12].copy_from_slice(&sequence.to_ne_bytes());
+
+        self.buffer
+    }
+}
+
+/// A NETLINK_ROUTE socket in the network namespace of the thread that opened it.
+pub struct Netlink {
+    socket: OwnedFd,
+    sequence: u32,
+}
+
+impl Netlink {
+    pub fn open() -> nix::Result<Self> {
+        let socket = socket(
+            AddressFamily::Netlink,
+            SockType::Raw,
+            SockFlag::SOCK_CLOEXEC,
+            SockProtocol::NetlinkRoute,
+        )?;
+        bind(socket.as_raw_fd(), &NetlinkAddr::new(0, 0))?;
+
+        Ok(Netlink {
+            socket,
+            sequence: 0,
+        })
+    }
+
+    /// Sends `message` and waits for the kernel to acknowledge it.
+    fn request(&mut self, message: Message) -> nix::Result<()> {
+        self.sequence += 1;
+        let request = message.finish(self.sequence);
+        send(self.socket.as_raw_fd(), &request, MsgFlags::empty())?;
+
+        let mut response = vec![0u8; 8192];
+        loop {
+            let length = recv(self.socket.as_raw_fd(), &mut response, MsgFlags::empty())?;
+            let mut offset = 0;
+
+            while offset + NLMSG_HEADER_SIZE <= length {
+                let field = |at: usize| {
+                    u32::from_ne_bytes(response[offset + at..offset + at + 4].try_into().unwrap())
+                };
+                let message_length = field(0) as usize;
+                let message_type = u16::from_ne_bytes([response[offset + 4], response[offset + 5]]);
+
+                if message_type == NLMSG_ERROR && field(8) == self.sequence {
+                    // The acknowledgement is an error message with errno 0.
+                    return match field(NLMSG_HEADER_SIZE) as i32 {
+                        0 => Ok(()),
+                        error => Err(Errno::from_raw(-error)),
+                    };
+                }
+
+                if message_length < NLMSG_HEADER_SIZE {
+                    break;
+                }
+                offset += message_length.next_multiple_of(4);
+            }
+        }
+    }
+
+    pub fn add_bridge(&mut self, name: &str) -> nix::Result<()> {
+        let mut message = Message::new(RTM_NEWLINK, NLM_F_CREATE | NLM_F_EXCL, &ifinfomsg(0, 0, 0));
+        message
+            .attribute(IFLA_IFNAME, &nul_terminated(name))
+            .nested(IFLA_LINKINFO, |linkinfo| {
+                linkinfo.attribute(IFLA_INFO_KIND, b"bridge");
+            });
+
+        self.request(message)
+    }
+
+    /// Creates a veth pair whose `peer_name` end is created right in the
+    /// network namespace `peer_netns`.
+    pub fn add_veth(
+        &mut self,
+        name: &str,
+        peer_name: &str,
+        peer_netns: BorrowedFd,
+    ) -> nix::Result<()> {
+        let mut message = Message::new(RTM_NEWLINK, NLM_F_CREATE | NLM_F_EXCL, &ifinfomsg(0, 0, 0));
+        message
+            .attribute(IFLA_IFNAME, &nul_terminated(name))
+            .nested(IFLA_LINKINFO, |linkinfo| {
+                linkinfo
+                    .attribute(IFLA_INFO_KIND, b"veth")
+                    .nested(IFLA_INFO_DATA, |data| {
+                        data.nested(VETH_INFO_PEER, |peer| {
+                            peer.buffer.extend_from_slice(&ifinfomsg(0, 0, 0));
+                            peer.attribute(IFLA_IFNAME, &nul_terminated(peer_name))
+                                .attribute(IFLA_NET_NS_FD, &peer_netns.as_raw_fd().to_ne_bytes());
+                        });
+                    });
+            });
+
+        self.request(message)
+    }
+
+    pub fn set_master(&mut self, index: u32, master_index: u32) -> nix::Result<()> {
+        let mut message = Message::new(RTM_NEWLINK, 0, &ifinfomsg(index, 0, 0));
+        message.attribute(IFLA_MASTER, &master_index.to_ne_bytes());
+
+        self.request(message)
+    }
+
+    pub fn set_up(&mut self, index: u32) -> nix::Result<()> {
+        self.request(Message::new(
+            RTM_NEWLINK,
+            0,
+            &ifinfomsg(index, IFF_UP, IFF_UP),
+        ))
+    }
+
+    pub fn add_address(
+        &mut self,
+        index: u32,
+        address: Ipv4Addr,
+        prefix_length: u8,
+    ) -> nix::Result<()> {
+        let mut header = vec![AF_INET, prefix_length, 0, RT_SCOPE_UNIVERSE];
+        header.extend_from_slice(&index.to_ne_bytes());
+
+        let mut message = Message::new(RTM_NEWADDR, NLM_F_CREATE | NLM_F_EXCL, &header);
+        message
+            .attribute(IFA_LOCAL, &address.octets())
+            .attribute(IFA_ADDRESS, &address.octets());
+
+        self.request(message)
+    }
+
+    pub fn add_default_route(&mut self, gateway: Ipv4Addr) -> nix::Result<()> {
+        let mut header = vec![
+            AF_INET,
+            0,
+            0,
+            0,
+            RT_TABLE_MAIN,
+            RTPROT_BOOT,
+            RT_SCOPE_UNIVERSE,
+            RTN_UNICAST,
+        ];
+        header.extend_from_slice(&0u32.to_ne_bytes());
+
+        let mut message = Message::new(RTM_NEWROUTE, NLM_F_CREATE | NLM_F_EXCL, &header);
+        message.attribute(RTA_GATEWAY, &gateway.octets());
+
+        self.request(message)
+    }
+}
+
+fn nul_terminated(name: &str) -> Vec<u8> {
+    let mut bytes = name.as_bytes().to_vec();
+    bytes.push(0);
+
+    bytes
+}
+
+pub fn link_index(name: &str) -> nix::Result<u32> {
+    nix::net::if_::if_nametoindex(name)
+}
@@ -0,0 +1,449 @@
+use crate::init;
+use crate::netlink::{self, Netlink};
+use crate::ports::{self, PortMapping};
+use crate::slirp::{self, Slirp};
+use anyhow::{bail, Context, Result};
+use clap::ValueEnum;
+use log::{error, info, warn};
+use nix::errno::Errno;
+use nix::sched::{setns, unshare, CloneFlags};
+use nix::sys::prctl;
+use nix::sys::signal::{kill, Signal};
+use nix::unistd::{fork, ForkResult, Pid};
+use std::fs::File;
+use std::io::prelude::*;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::os::fd::AsFd;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum NetworkMode {
+    /// Share the host network stack.
+    Host,
+    /// A network namespace with nothing but loopback.
+    None,
+    /// A network namespace connected to the snow0 host bridge, with NAT to
+    /// the outside.
+    Bridge,
+    /// A network namespace with a userspace NAT in snow, works rootless. The
+    /// host loopback is reachable at 10.0.2.2.
+    Slirp,
+}
+
+const BRIDGE: &str = "snow0";
+const BRIDGE_ADDRESS: Ipv4Addr = Ipv4Addr::new(10, 111, 0, 1);
+const BRIDGE_PREFIX_LENGTH: u8 = 24;
+const CONTAINER_INTERFACE: &str = "eth0";
+const LEASES_DIR: &str = "/run/snow/leases";
+/// What the container gets when the host only knows local resolvers, like
+/// Docker does.
+const FALLBACK_NAMESERVERS: [&str; 2] = ["8.8.8.8", "8.8.4.4"];
+
+fn bridge_network() -> Ipv4Addr {
+    let mask = u32::MAX << (32 - BRIDGE_PREFIX_LENGTH);
+    Ipv4Addr::from(u32::from(BRIDGE_ADDRESS) & mask)
+}
+
+/// An address on the bridge, held by the snow process which owns the lease
+/// file named after it.
+struct Lease {
+    path: PathBuf,
+    address: Ipv4Addr,
+}
+
+impl Lease {
+    fn try_create(path: &Path) -> Result<bool> {
+        match File::options().write(true).create_new(true).open(path) {
+            Ok(mut lease) => {
+                write!(lease, "{}", std::process::id())?;
+                Ok(true)
+            }
+            Err(err) if err.kind() == std::io::ErrorKind::AlreadyExists => Ok(false),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    /// A lease whose owner is gone, left behind by a snow that was killed.
+    fn is_stale(path: &Path) -> bool {
+        let Ok(owner) = std::fs::read_to_string(path) else {
+            return false;
+        };
+
+        match owner.trim().parse() {
+            Ok(pid) => kill(Pid::from_raw(pid), None) == Err(Errno::ESRCH),
+            Err(_) => true,
+        }
+    }
+
+    fn acquire() -> Result<Self> {
+        std::fs::create_dir_all(LEASES_DIR)?;
+
+        let network = u32::from(bridge_network());
+        let hosts = (1u32 << (32 - BRIDGE_PREFIX_LENGTH)) - 1;
+        for host in 1..hosts {
+            let address = Ipv4Addr::from(network + host);
+            if address == BRIDGE_ADDRESS {
+                continue;
+            }
+
+            let path = Path::new(LEASES_DIR).join(address.to_string());
+            let mut acquired = Lease::try_create(&path)?;
+            if !acquired && Lease::is_stale(&path) {
+                let _ = std::fs::remove_file(&path);
+                acquired = Lease::try_create(&path)?;
+            }
+
+            if acquired {
+                return Ok(Lease { path, address });
+            }
+        }
+
+        bail!("no free address left on {BRIDGE}");
+    }
+}
+
+impl Drop for Lease {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+/// The network the container joins.
+pub struct Network {
+    pub mode: NetworkMode,
+    netns: Option<File>,
+    pub address: Option<Ipv4Addr>,
+    lease: Option<Lease>,
+    tap: Option<File>,
+}
+
+/// Creates a network namespace and returns a handle to it. A thread does the
+/// unsharing so we stay in the host's.
+fn new_netns() -> Result<File> {
+    std::thread::spawn(|| -> Result<File> {
+        unshare(CloneFlags::CLONE_NEWNET)?;
+        Ok(File::open("/proc/thread-self/ns/net")?)
+    })
+    .join()
+    .expect("network namespace thread panicked")
+}
+
+/// Runs `f` on a thread in the network namespace `netns`.
+pub fn in_netns<T: Send>(netns: &File, f: impl FnOnce() -> Result<T> + Send) -> Result<T> {
+    std::thread::scope(|scope| {
+        scope
+            .spawn(|| {
+                setns(netns, CloneFlags::CLONE_NEWNET)?;
+                f()
+            })
+            .join()
+            .expect("network namespace thread panicked")
+    })
+}
+
+fn iptables(args: &[&str]) -> Result<bool> {
+    Ok(Command::new("iptables")
+        .args(args)
+        .stderr(std::process::Stdio::null())
+        .status()?
+        .success())
+}
+
+/// Masquerades traffic from the bridge and lets it through the FORWARD chain,
+/// which Docker sets to drop.
+fn enable_nat() -> Result<()> {
+    std::fs::write("/proc/sys/net/ipv4/ip_forward", "1")?;
+
+    let subnet = format!("{}/{BRIDGE_PREFIX_LENGTH}", bridge_network());
+    let rules: [(&str, &str, &[&str]); 3] = [
+        (
+            "nat",
+            "POSTROUTING",
+            &["-s", &subnet, "!", "-o", BRIDGE, "-j", "MASQUERADE"],
+        ),
+        ("filter", "FORWARD", &["-i", BRIDGE, "-j", "ACCEPT"]),
+        (
+            "filter",
+            "FORWARD",
+            &[
+                "-o",
+                BRIDGE,
+                "-m",
+                "conntrack",
+                "--ctstate",
+                "RELATED,ESTABLISHED",
+                "-j",
+                "ACCEPT",
+            ],
+        ),
+    ];
+
+    for (table, chain, rule) in rules {
+        let check = [&["-t", table, "-C", chain], rule].concat();
+        match iptables(&check) {
+            Ok(true) => continue,
+            Ok(false) => {}
+            Err(err) => {
+                warn!("failed running iptables, the container won't reach beyond the host: {err}");
+                return Ok(());
+            }
+        }
+
+        let insert = [&["-t", table, "-I", chain], rule].concat();
+        if !iptables(&insert)? {
+            bail!("iptables failed adding {insert:?}");
+        }
+    }
+
+    Ok(())
+}
+
+fn setup_bridge(netlink: &mut Netlink) -> Result<u32> {
+    if let Ok(index) = netlink::link_index(BRIDGE) {
+        return Ok(index);
+    }
+
+    info!("creating bridge {BRIDGE} with address {BRIDGE_ADDRESS}/{BRIDGE_PREFIX_LENGTH}");
+    // Another snow may be racing us to it.
+    match netlink.add_bridge(BRIDGE) {
+        Ok(()) | Err(Errno::EEXIST) => {}
+        Err(err) => return Err(err).context("failed creating bridge"),
+    }
+
+    let index = netlink::link_index(BRIDGE)?;
+    match netlink.add_address(index, BRIDGE_ADDRESS, BRIDGE_PREFIX_LENGTH) {
+        Ok(()) | Err(Errno::EEXIST) => {}
+        Err(err) => return Err(err).context("failed adding bridge address"),
+    }
+    netlink.set_up(index)?;
+
+    Ok(index)
+}
+
+fn setup_loopback() -> Result<()> {
+    Netlink::open()?.set_up(netlink::link_index("lo")?)?;
+
+    Ok(())
+}
+
+/// Prepares the network for `mode`, from the host network namespace and
+/// before the container process exists.
+pub fn setup(mode: NetworkMode) -> Result<Network> {
+    let mut network = Network {
+        mode,
+        netns: None,
+        address: None,
+        lease: None,
+        tap: None,
+    };
+
+    if mode == NetworkMode::Host {
+        return Ok(network);
+    }
+
+    let netns = new_netns()?;
+    in_netns(&netns, setup_loopback)?;
+
+    if mode == NetworkMode::Bridge {
+        let lease = Lease::acquire()?;
+        let address = lease.address;
+
+        let mut netlink = Netlink::open()?;
+        let bridge_index = setup_bridge(&mut netlink)?;
+        enable_nat()?;
+
+        let veth = format!("vsnow{}", std::process::id());
+        info!("connecting {veth} to {BRIDGE}, container address {address}");
+        netlink
+            .add_veth(&veth, CONTAINER_INTERFACE, netns.as_fd())
+            .context("failed creating veth pair")?;
+        let veth_index = netlink::link_index(&veth)?;
+        netlink.set_master(veth_index, bridge_index)?;
+        netlink.set_up(veth_index)?;
+
+        in_netns(&netns, || {
+            let mut netlink = Netlink::open()?;
+            let index = netlink::link_index(CONTAINER_INTERFACE)?;
+            netlink.add_address(index, address, BRIDGE_PREFIX_LENGTH)?;
+            netlink.set_up(index)?;
+            netlink.add_default_route(BRIDGE_ADDRESS)?;
+
+            Ok(())
+        })?;
+
+        network.address = Some(address);
+        network.lease = Some(lease);
+    }
+
+    if mode == NetworkMode::Slirp {
+        info!(
+            "creating {CONTAINER_INTERFACE} tap, container address {}",
+            slirp::CONTAINER_ADDRESS
+        );
+        let tap = in_netns(&netns, || {
+            let tap = slirp::create_tap(CONTAINER_INTERFACE).context("failed creating tap")?;
+
+            let mut netlink = Netlink::open()?;
+            let index = netlink::link_index(CONTAINER_INTERFACE)?;
+            netlink.add_address(index, slirp::CONTAINER_ADDRESS, slirp::PREFIX_LENGTH)?;
+            netlink.set_up(index)?;
+            netlink.add_default_route(slirp::GATEWAY)?;
+
+            Ok(tap)
+        })?;
+
+        network.address = Some(slirp::CONTAINER_ADDRESS);
+        network.tap = Some(tap);
+    }
+
+    network.netns = Some(netns);
+
+    Ok(network)
+}
+
+/// The nameserver slirp forwards the container's DNS to, the host's own
+/// first one since the queries are sent from the host.
+fn host_nameserver() -> IpAddr {
+    let resolv_conf = std::fs::read_to_string("/etc/resolv.conf").unwrap_or_default();
+
+    resolv_conf
+        .lines()
+        .filter_map(|line| line.trim().strip_prefix("nameserver"))
+        .find_map(|address| address.trim().parse().ok())
+        .unwrap_or_else(|| FALLBACK_NAMESERVERS[0].parse().unwrap())
+}
+
+/// Runs `helper` in a process of its own on the host, which goes away with
+/// us. Network helpers need threads, which we can't create once we unshared
+/// the PID namespace.
+fn spawn_helper(helper: impl FnOnce() -> Result<()>) -> Result<()> {
+    // SAFETY: we are still single threaded at this point.
+    if let ForkResult::Child = unsafe { fork()? } {
+        prctl::set_pdeathsig(Signal::SIGKILL)?;
+        // Signals are for the container, we stop when snow does.
+        init::block_handled_signals()?;
+
+        if let Err(err) = helper() {
+            error!("network helper stopped: {err:?}");
+        }
+        std::process::exit(1);
+    }
+
+    Ok(())
+}
+
+impl Network {
+    /// Moves the calling process, the container, into the network namespace.
+    /// The lease stays with the process on the host, which releases it once
+    /// the container is done.
+    pub fn enter(&mut self) -> Result<()> {
+        if let Some(lease) = self.lease.take() {
+            std::mem::forget(lease);
+        }
+
+        if let Some(netns) = self.netns.take() {
+            setns(netns, CloneFlags::CLONE_NEWNET)?;
+        }
+
+        Ok(())
+    }
+
+    /// Starts the userspace NAT for slirp networking.
+    pub fn serve(&mut self) -> Result<()> {
+        let Some(tap) = self.tap.take() else {
+            return Ok(());
+        };
+
+        let nameserver = SocketAddr::new(host_nameserver(), 53);
+        spawn_helper(move || Slirp::new(tap, nameserver).run())
+    }
+
+    /// Starts forwarding `ports` of the host into the container.
+    pub fn publish(&self, ports: &[PortMapping]) -> Result<()> {
+        if ports.is_empty() {
+            return Ok(());
+        }
+        let Some(netns) = &self.netns else {
+            warn!("publishing ports has no effect with the host network");
+            return Ok(());
+        };
+
+        let listeners = ports::bind(ports)?;
+        let netns = netns.try_clone()?;
+        spawn_helper(move || ports::forward(listeners, netns))
+    }
+
+    /// The host's resolv.conf for the host network. Loopback nameservers
+    /// aren't reachable from anywhere else, so they are dropped in favor of the
+    /// upstream ones behind systemd-resolved or public ones.
+    fn resolv_conf(&self) -> Result<String> {
+        let host_resolv_conf = std::fs::read_to_string("/etc/resolv.conf").unwrap_or_default();
+
+        match self.mode {
+            NetworkMode::Host => return Ok(host_resolv_conf),
+            NetworkMode::None => return Ok(String::new()),
+            NetworkMode::Slirp => return Ok(format!("nameserver {}\n", slirp::NAMESERVER)),
+            NetworkMode::Bridge => {}
+        }
+
+        let is_loopback_nameserver = |line: &str| {
+            line.split_whitespace()
+                .collect::<Vec<_>>()
+                .as_slice()
+                .strip_prefix(&["nameserver"])
+                .and_then(|rest| rest.first())
+                .and_then(|address| address.parse::<std::net::IpAddr>().ok())
+                .is_some_and(|address| address.is_loopback())
+        };
+        let has_nameserver = |resolv_conf: &str| {
+            resolv_conf.lines().any(|line| {
+                line.trim_start().starts_with("nameserver") && !is_loopback_nameserver(line)
+            })
+        };
+
+        let mut resolv_conf = host_resolv_conf;
+        if !has_nameserver(&resolv_conf) {
+            if let Ok(upstream) = std::fs::read_to_string("/run/systemd/resolve/resolv.conf") {
+                resolv_conf = upstream;
+            }
+        }
+
+        let mut resolv_conf: String = resolv_conf
+            .lines()
+            .filter(|line| !is_loopback_nameserver(line))
+            .map(|line| format!("{line}\n"))
+            .collect();
+        if !has_nameserver(&resolv_conf) {
+            for nameserver in FALLBACK_NAMESERVERS {
+                resolv_conf.push_str(&format!("nameserver {nameserver}\n"));
+            }
+        }
+
+        Ok(resolv_conf)
+    }
+
+    /// Writes the hostname, hosts and resolv.conf files
+    /// `mount::network_configuration` binds into the container.
+    pub fn write_configuration(&self, target: PathBuf, hostname: &str) -> Result<()> {
+        std::fs::create_dir(&target)?;
+
+        let address = self
+            .address
+            .unwrap_or(Ipv4Addr::new(127, 0, 1, 1))
+            .to_string();
+
+        std::fs::write(target.join("hostname"), format!("{hostname}\n"))?;
+        std::fs::write(
+            target.join("hosts"),
+            format!(
+                "127.0.0.1\tlocalhost\n\
+                 ::1\tlocalhost ip6-localhost ip6-loopback\n\
+                 {address}\t{hostname}\n"
+            ),
+        )?;
+        std::fs::write(target.join("resolv.conf"), self.resolv_conf()?)?;
+
+        Ok(())
+    }
+}
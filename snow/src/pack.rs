@@ -0,0 +1,90 @@
+use crate::cli::PackOptions;
+use crate::{config, payload, signature, verity};
+use anyhow::{Context, Result};
+use log::info;
+use std::fs::File;
+use std::io::prelude::*;
+use std::path::Path;
+
+pub fn pack(options: &PackOptions) -> Result<()> {
+    let image_config = match &options.config {
+        Some(path) => {
+            let image_config: config::ImageConfig =
+                serde_json::from_slice(&std::fs::read(path)?)
+                    .with_context(|| format!("failed parsing image config {}", path.display()))?;
+            Some(serde_json::to_vec(&image_config)?)
+        }
+        None => None,
+    };
+
+    stamp(
+        &options.image,
+        image_config.as_deref(),
+        &options.runtime,
+        &options.out,
+        options.signing_key.as_deref(),
+        options.verity,
+    )
+}
+
+/// Stamps the squashfs `image` and its serialized `image_config` into a copy of
+/// `runtime` at `out`, signed with the key in `signing_key` and with a
+/// dm-verity hash tree if asked to.
+pub fn stamp(
+    image: &Path,
+    image_config: Option<&[u8]>,
+    runtime: &Path,
+    out: &Path,
+    signing_key: Option<&Path>,
+    verity: bool,
+) -> Result<()> {
+    let signing_key = signing_key.map(signature::read_signing_key).transpose()?;
+
+    info!("hashing {}", image.display());
+    let mut metadata = payload::Metadata {
+        squashfs_sha256: payload::sha256(File::open(image)?)?,
+        config_sha256: image_config.map(payload::sha256).transpose()?,
+        verity: None,
+    };
+    info!("image sha256 {}", metadata.squashfs_sha256);
+
+    let mut hash_tree = Vec::new();
+    if verity {
+        info!("building verity hash tree");
+        let (tree, verity_metadata) = verity::hash_tree(File::open(image)?)?;
+        info!("verity root hash {}", verity_metadata.root_hash);
+        hash_tree = tree;
+        metadata.verity = Some(verity_metadata);
+    }
+
+    let metadata = serde_json::to_vec(&metadata)?;
+
+    let mut sections: Vec<(&str, Box<dyn Read>)> = vec![
+        (payload::SQUASHFS_SECTION, Box::new(File::open(image)?)),
+        (payload::METADATA_SECTION, Box::new(metadata.as_slice())),
+    ];
+
+    if verity {
+        sections.push((payload::VERITY_SECTION, Box::new(hash_tree.as_slice())));
+    }
+
+    if let Some(image_config) = image_config {
+        sections.push((payload::CONFIG_SECTION, Box::new(image_config)));
+    }
+
+    let signature = signing_key.map(|key| signature::sign(&key, &metadata));
+    if let Some(signature) = &signature {
+        info!("signing image");
+        sections.push((payload::SIGNATURE_SECTION, Box::new(signature.as_slice())));
+    }
+
+    info!(
+        "packing {} into {} using runtime {}",
+        image.display(),
+        out.display(),
+        runtime.display()
+    );
+    payload::pack(runtime, sections, out)?;
+
+    Ok(())
+}
@@ -0,0 +1,508 @@
+use crate::verity::VerityMetadata;
+use anyhow::{bail, Context, Result};
+use goblin::container::Ctx;
+use goblin::elf::section_header::{SectionHeader, SHN_XINDEX, SHT_NOBITS};
+use goblin::elf::Elf;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::fs::{File, OpenOptions};
+use std::io::prelude::*;
+use std::io::SeekFrom;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::Path;
+
+// A packed snow binary looks like this:
+//
+// | generic snow runtime (ELF) | section | section | ... | section table | trailer |
+//
+// Every section starts on a page boundary so the squashfs image can be used
+// directly as the backing range of a loop device.
+//
+// Runtimes which carry the image in ELF sections instead, for example added with
+// `objcopy --add-section .squashfs=image.squashfs`, are supported as well. There
+// `.squashfs` holds the image and `.snow-<name>` holds the section `<name>`.
+const TRAILER_MAGIC: &[u8; 8] = b"SNOWPACK";
+const TRAILER_VERSION: u32 = 1;
+const TRAILER_SIZE: u64 = 24;
+const SECTION_NAME_LEN: usize = 16;
+const SECTION_ENTRY_SIZE: u64 = SECTION_NAME_LEN as u64 + 16;
+const SECTION_ALIGNMENT: u64 = 4096;
+const ELF_SQUASHFS_SECTION: &str = ".squashfs";
+const ELF_SECTION_PREFIX: &str = ".snow-";
+
+pub const SQUASHFS_SECTION: &str = "squashfs";
+pub const METADATA_SECTION: &str = "metadata";
+pub const SIGNATURE_SECTION: &str = "signature";
+pub const VERITY_SECTION: &str = "verity";
+pub const CONFIG_SECTION: &str = "config";
+
+/// Describes the image, stored as JSON in the metadata section.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Metadata {
+    pub squashfs_sha256: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub config_sha256: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub verity: Option<VerityMetadata>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Section {
+    pub name: String,
+    pub offset: u64,
+    pub size: u64,
+}
+
+#[derive(Debug, Clone)]
+pub struct Payload {
+    /// Size of the runtime executable the sections were appended to.
+    pub runtime_size: u64,
+    pub sections: Vec<Section>,
+}
+
+impl Payload {
+    /// Finds the sections of `file`, returns `None` for a generic runtime which
+    /// carries no image.
+    pub fn locate(file: &mut File) -> Result<Option<Payload>> {
+        if let Some(payload) = Self::locate_trailer(file)? {
+            return Ok(Some(payload));
+        }
+
+        let sections = locate_elf_sections(file)?;
+        if !sections
+            .iter()
+            .any(|section| section.name == SQUASHFS_SECTION)
+        {
+            return Ok(None);
+        }
+
+        Ok(Some(Payload {
+            // The sections are part of the ELF file itself.
+            runtime_size: file.metadata()?.len(),
+            sections,
+        }))
+    }
+
+    fn locate_trailer(file: &mut File) -> Result<Option<Payload>> {
+        let file_size = file.metadata()?.len();
+        if file_size < TRAILER_SIZE {
+            return Ok(None);
+        }
+
+        let mut trailer = [0u8; TRAILER_SIZE as usize];
+        file.seek(SeekFrom::Start(file_size - TRAILER_SIZE))?;
+        file.read_exact(&mut trailer)?;
+
+        if &trailer[0..8] != TRAILER_MAGIC {
+            return Ok(None);
+        }
+
+        let version = u32::from_le_bytes(trailer[8..12].try_into()?);
+        if version != TRAILER_VERSION {
+            bail!("unsupported payload version {version}");
+        }
+
+        let section_count = u64::from(u32::from_le_bytes(trailer[12..16].try_into()?));
+        let runtime_size = u64::from_le_bytes(trailer[16..24].try_into()?);
+
+        let table_size = section_count * SECTION_ENTRY_SIZE;
+        let table_offset = (file_size - TRAILER_SIZE)
+            .checked_sub(table_size)
+            .context("payload section table is truncated")?;
+
+        let mut table = vec![0u8; table_size as usize];
+        file.seek(SeekFrom::Start(table_offset))?;
+        file.read_exact(&mut table)?;
+
+        let mut sections = Vec::new();
+        for entry in table.chunks_exact(SECTION_ENTRY_SIZE as usize) {
+            let name = &entry[..SECTION_NAME_LEN];
+            let name_len = name
+                .iter()
+                .position(|&b| b == 0)
+                .unwrap_or(SECTION_NAME_LEN);
+            let section = Section {
+                name: String::from_utf8(name[..name_len].to_vec())?,
+                offset: u64::from_le_bytes(entry[16..24].try_into()?),
+                size: u64::from_le_bytes(entry[24..32].try_into()?),
+            };
+
+            let Some(end) = section.offset.checked_add(section.size) else {
+                bail!("payload section {} is out of bounds", section.name);
+            };
+            if section.offset < runtime_size || end > table_offset {
+                bail!("payload section {} is out of bounds", section.name);
+            }
+
+            sections.push(section);
+        }
+
+        Ok(Some(Payload {
+            runtime_size,
+            sections,
+        }))
+    }
+
+    pub fn section(&self, name: &str) -> Option<&Section> {
+        self.sections.iter().find(|section| section.name == name)
+    }
+
+    pub fn read_section(&self, file: &mut File, name: &str) -> Result<Option<Vec<u8>>> {
+        let Some(section) = self.section(name) else {
+            return Ok(None);
+        };
+
+        let mut data = Vec::new();
+        section_reader(file, section)?.read_to_end(&mut data)?;
+
+        Ok(Some(data))
+    }
+
+    pub fn metadata(&self, file: &mut File) -> Result<Option<Metadata>> {
+        match self.read_section(file, METADATA_SECTION)? {
+            Some(data) => Ok(Some(serde_json::from_slice(&data)?)),
+            None => Ok(None),
+        }
+    }
+}
+
+fn read_at(file: &mut File, offset: u64, size: u64) -> Result<Vec<u8>> {
+    let mut buffer = vec![0u8; size as usize];
+    file.seek(SeekFrom::Start(offset))?;
+    file.read_exact(&mut buffer)?;
+
+    Ok(buffer)
+}
+
+/// Looks up our sections in the ELF section headers of `file`. Only the headers
+/// and the section name table are read, never the whole executable.
+fn locate_elf_sections(file: &mut File) -> Result<Vec<Section>> {
+    let header = Elf::parse_header(&read_at(file, 0, 64)?)?;
+    let ctx = Ctx::new(header.container()?, header.endianness()?);
+    let entry_size = u64::from(header.e_shentsize);
+
+    if header.e_shoff == 0 {
+        return Ok(Vec::new());
+    }
+
+    // With more than SHN_LORESERVE sections the real count and string table
+    // index live in the first, otherwise unused, section header.
+    let first = SectionHeader::parse_from(&read_at(file, header.e_shoff, entry_size)?, 0, 1, ctx)?;
+    let count = match header.e_shnum {
+        0 => first[0].sh_size,
+        count => u64::from(count),
+    };
+    let strtab_index = match u32::from(header.e_shstrndx) {
+        SHN_XINDEX => first[0].sh_link as usize,
+        index => index as usize,
+    };
+
+    let table = read_at(file, header.e_shoff, count * entry_size)?;
+    let section_headers = SectionHeader::parse_from(&table, 0, count as usize, ctx)?;
+    let strtab_header = section_headers
+        .get(strtab_index)
+        .context("ELF section name table not found")?;
+    let strtab = read_at(file, strtab_header.sh_offset, strtab_header.sh_size)?;
+
+    let mut sections = Vec::new();
+    for section_header in section_headers.iter() {
+        let Some(name) = strtab
+            .get(section_header.sh_name..)
+            .and_then(|name| name.split(|&b| b == 0).next())
+        else {
+            continue;
+        };
+        let name = String::from_utf8_lossy(name);
+
+        let name = if name == ELF_SQUASHFS_SECTION {
+            SQUASHFS_SECTION
+        } else if let Some(name) = name.strip_prefix(ELF_SECTION_PREFIX) {
+            name
+        } else {
+            continue;
+        };
+
+        // sh_addr is where the section would be loaded in memory, we want where
+        // it is in the file.
+        if section_header.sh_type == SHT_NOBITS {
+            bail!("ELF section of {name} has no data in the file");
+        }
+
+        sections.push(Section {
+            name: name.to_string(),
+            offset: section_header.sh_offset,
+            size: section_header.sh_size,
+        });
+    }
+
+    Ok(sections)
+}
+
+pub fn section_reader<'a>(file: &'a mut File, section: &Section) -> Result<impl Read + 'a> {
+    file.seek(SeekFrom::Start(section.offset))?;
+
+    Ok(file.take(section.size))
+}
+
+pub fn sha256(mut reader: impl Read) -> Result<String> {
+    let mut hasher = Sha256::new();
+    std::io::copy(&mut reader, &mut hasher)?;
+
+    Ok(hex::encode(hasher.finalize()))
+}
+
+/// Size of the runtime part of `file`, which is the whole file for a generic runtime.
+fn runtime_size(file: &mut File) -> Result<u64> {
+    match Payload::locate(file)? {
+        Some(payload) => Ok(payload.runtime_size),
+        None => Ok(file.metadata()?.len()),
+    }
+}
+
+fn write_padding(out: &mut File, position: &mut u64) -> Result<()> {
+    let padding = position.next_multiple_of(SECTION_ALIGNMENT) - *position;
+    out.write_all(&vec![0u8; padding as usize])?;
+    *position += padding;
+
+    Ok(())
+}
+
+/// Writes `out` as a copy of the runtime in `runtime` (without any payload it may
+/// already carry) followed by the given sections.
+pub fn pack(runtime: &Path, sections: Vec<(&str, Box<dyn Read + '_>)>, out: &Path) -> Result<()> {
+    let mut runtime_file = File::open(runtime)
+        .with_context(|| format!("failed opening runtime {}", runtime.display()))?;
+    let runtime_size = runtime_size(&mut runtime_file)?;
+
+    let mut header = [0u8; 64];
+    runtime_file.seek(SeekFrom::Start(0))?;
+    runtime_file.read_exact(&mut header)?;
+    Elf::parse_header(&header)
+        .with_context(|| format!("{} is not an ELF executable", runtime.display()))?;
+
+    let mut out_file = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o755)
+        .open(out)
+        .with_context(|| format!("failed creating {}", out.display()))?;
+
+    runtime_file.seek(SeekFrom::Start(0))?;
+    let mut position = std::io::copy(&mut (&mut runtime_file).take(runtime_size), &mut out_file)?;
+    if position != runtime_size {
+        bail!("runtime {} is truncated", runtime.display());
+    }
+
+    let section_count = sections.len() as u32;
+    let mut table = Vec::new();
+    for (name, mut data) in sections {
+        if name.len() > SECTION_NAME_LEN {
+            bail!("section name {name} is too long");
+        }
+
+        write_padding(&mut out_file, &mut position)?;
+        let offset = position;
+        let size = std::io::copy(&mut data, &mut out_file)?;
+        position += size;
+
+        let mut entry_name = [0u8; SECTION_NAME_LEN];
+        entry_name[..name.len()].copy_from_slice(name.as_bytes());
+        table.extend_from_slice(&entry_name);
+        table.extend_from_slice(&offset.to_le_bytes());
+        table.extend_from_slice(&size.to_le_bytes());
+    }
+    write_padding(&mut out_file, &mut position)?;
+
+    out_file.write_all(&table)?;
+    out_file.write_all(TRAILER_MAGIC)?;
+    out_file.write_all(&TRAILER_VERSION.to_le_bytes())?;
+    out_file.write_all(&section_count.to_le_bytes())?;
+    out_file.write_all(&runtime_size.to_le_bytes())?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+    use std::process::Command;
+
+    /// How a test runtime is linked, the flags given to cc.
+    const LINKAGES: [(&str, &[&str]); 3] = [
+        ("pie", &["-fPIE", "-pie"]),
+        ("no-pie", &["-fno-PIE", "-no-pie"]),
+        ("static", &["-static"]),
+    ];
+
+    /// A scratch directory of the test `name`, emptied first.
+    fn scratch_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("snow-payload-{}-{name}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    /// Builds a trivial C program linked with `flags` to stand in for the runtime.
+    fn build_runtime(dir: &Path, flags: &[&str]) -> PathBuf {
+        let source = dir.join("runtime.c");
+        std::fs::write(&source, "int main(void) { return 0; }\n").unwrap();
+        let runtime = dir.join("runtime");
+        let status = Command::new("cc")
+            .args(flags)
+            .arg("-o")
+            .arg(&runtime)
+            .arg(&source)
+            .status()
+            .expect("the payload tests need a C compiler");
+        assert!(status.success(), "cc {flags:?} failed");
+        runtime
+    }
+
+    /// Data that isn't the same in every section, so mixed up offsets show.
+    fn data(seed: u8, size: usize) -> Vec<u8> {
+        (0..size)
+            .map(|i| (i as u8).wrapping_mul(31).wrapping_add(seed))
+            .collect()
+    }
+
+    fn read(payload: &Payload, path: &Path, name: &str) -> Option<Vec<u8>> {
+        payload
+            .read_section(&mut File::open(path).unwrap(), name)
+            .unwrap()
+    }
+
+    #[test]
+    fn generic_runtime_has_no_payload() {
+        for (linkage, flags) in LINKAGES {
+            let dir = scratch_dir(&format!("generic-{linkage}"));
+            let runtime = build_runtime(&dir, flags);
+
+            assert!(Payload::locate(&mut File::open(&runtime).unwrap())
+                .unwrap()
+                .is_none());
+            std::fs::remove_dir_all(dir).unwrap();
+        }
+    }
+
+    #[test]
+    fn pack_then_locate() {
+        for (linkage, flags) in LINKAGES {
+            let dir = scratch_dir(&format!("trailer-{linkage}"));
+            let runtime = build_runtime(&dir, flags);
+            let runtime_len = std::fs::metadata(&runtime).unwrap().len();
+            let squashfs = data(1, 10_000);
+            let metadata = data(2, 100);
+
+            let packed = dir.join("packed");
+            pack(
+                &runtime,
+                vec![
+                    (SQUASHFS_SECTION, Box::new(squashfs.as_slice())),
+                    (METADATA_SECTION, Box::new(metadata.as_slice())),
+                ],
+                &packed,
+            )
+            .unwrap();
+
+            let payload = Payload::locate(&mut File::open(&packed).unwrap())
+                .unwrap()
+                .expect("packed runtime has no payload");
+            assert_eq!(payload.runtime_size, runtime_len);
+            for section in &payload.sections {
+                assert_eq!(section.offset % SECTION_ALIGNMENT, 0);
+            }
+            assert_eq!(read(&payload, &packed, SQUASHFS_SECTION), Some(squashfs));
+            assert_eq!(read(&payload, &packed, METADATA_SECTION), Some(metadata));
+            assert_eq!(read(&payload, &packed, CONFIG_SECTION), None);
+
+            // Packing a packed runtime replaces its payload.
+            let repacked = dir.join("repacked");
+            let config = data(3, 50);
+            pack(
+                &packed,
+                vec![(CONFIG_SECTION, Box::new(config.as_slice()))],
+                &repacked,
+            )
+            .unwrap();
+            let payload = Payload::locate(&mut File::open(&repacked).unwrap())
+                .unwrap()
+                .unwrap();
+            assert_eq!(payload.runtime_size, runtime_len);
+            assert_eq!(payload.sections.len(), 1);
+            assert_eq!(read(&payload, &repacked, CONFIG_SECTION), Some(config));
+
+            // The runtime part still runs.
+            assert!(Command::new(&repacked).status().unwrap().success());
+            std::fs::remove_dir_all(dir).unwrap();
+        }
+    }
+
+    #[test]
+    fn locate_objcopy_sections() {
+        for (linkage, flags) in LINKAGES {
+            let dir = scratch_dir(&format!("objcopy-{linkage}"));
+            let runtime = build_runtime(&dir, flags);
+            let squashfs = data(4, 10_000);
+            let metadata = data(5, 100);
+            std::fs::write(dir.join("squashfs"), &squashfs).unwrap();
+            std::fs::write(dir.join("metadata"), &metadata).unwrap();
+
+            let stamped = dir.join("stamped");
+            let status = Command::new("objcopy")
+                .arg(format!(
+                    "--add-section={ELF_SQUASHFS_SECTION}={}",
+                    dir.join("squashfs").display()
+                ))
+                .arg(format!(
+                    "--add-section={ELF_SECTION_PREFIX}{METADATA_SECTION}={}",
+                    dir.join("metadata").display()
+                ))
+                .arg(&runtime)
+                .arg(&stamped)
+                .status()
+                .expect("the payload tests need objcopy");
+            assert!(status.success(), "objcopy failed");
+
+            let payload = Payload::locate(&mut File::open(&stamped).unwrap())
+                .unwrap()
+                .expect("stamped runtime has no payload");
+            assert_eq!(
+                payload.runtime_size,
+                std::fs::metadata(&stamped).unwrap().len()
+            );
+            assert_eq!(read(&payload, &stamped, SQUASHFS_SECTION), Some(squashfs));
+            assert_eq!(read(&payload, &stamped, METADATA_SECTION), Some(metadata));
+            assert!(Command::new(&stamped).status().unwrap().success());
+            std::fs::remove_dir_all(dir).unwrap();
+        }
+    }
+
+    #[test]
+    fn overflowing_section_is_rejected() {
+        let dir = scratch_dir("overflow");
+        let runtime = build_runtime(&dir, &[]);
+        let packed = dir.join("packed");
+        pack(
+            &runtime,
+            vec![(SQUASHFS_SECTION, Box::new([0u8; 10].as_slice()))],
+            &packed,
+        )
+        .unwrap();
+
+        // Point the only section's size at the end of the address space.
+        let mut file = File::options()
+            .read(true)
+            .write(true)
+            .open(&packed)
+            .unwrap();
+        let size_offset = file.metadata().unwrap().len() - TRAILER_SIZE - 8;
+        file.seek(SeekFrom::Start(size_offset)).unwrap();
+        file.write_all(&u64::MAX.to_le_bytes()).unwrap();
+
+        let err = Payload::locate(&mut file).unwrap_err();
+        assert!(err.to_string().contains("out of bounds"), "{err}");
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,88 @@
+use anyhow::{bail, Context, Result};
+use nix::errno::Errno;
+use nix::fcntl::{Flock, FlockArg};
+use nix::sys::stat;
+use nix::unistd;
+use std::fs::File;
+use std::path::{Path, PathBuf};
+
+/// Records the digest of the image the upper layer was made on.
+const IMAGE_FILE: &str = "image";
+const LOCK_FILE: &str = "lock";
+
+/// A host directory holding the overlay upper and work directories across
+/// runs, locked for as long as it lives.
+pub struct PersistDir {
+    path: PathBuf,
+    _lock: Flock<File>,
+}
+
+impl PersistDir {
+    /// Opens `path` for the image with digest `image_digest`, creating it on
+    /// first use. Changes made on another image wouldn't make sense on this
+    /// one so those are refused, as is a directory another snow is using.
+    pub fn open(path: &Path, image_digest: &str) -> Result<Self> {
+        std::fs::create_dir_all(path)
+            .with_context(|| format!("failed creating {}", path.display()))?;
+        PersistDir::open_with(path, image_digest)
+    }
+
+    /// Opens `path` only if a container already kept its changes there, a
+    /// mistyped path is no empty set of changes.
+    pub fn open_existing(path: &Path, image_digest: &str) -> Result<Self> {
+        if !path.is_dir() {
+            bail!("{} does not exist", path.display());
+        }
+        if !path.join(IMAGE_FILE).exists() {
+            bail!(
+                "{} holds no changes, it has no image digest recorded",
+                path.display()
+            );
+        }
+        PersistDir::open_with(path, image_digest)
+    }
+
+    fn open_with(path: &Path, image_digest: &str) -> Result<Self> {
+        let path = path.canonicalize()?;
+
+        let lock = File::create(path.join(LOCK_FILE))?;
+        let lock = match Flock::lock(lock, FlockArg::LockExclusiveNonblock) {
+            Ok(lock) => lock,
+            Err((_, Errno::EWOULDBLOCK)) => {
+                bail!("{} is in use by another snow", path.display())
+            }
+            Err((_, err)) => return Err(err.into()),
+        };
+
+        let image_file = path.join(IMAGE_FILE);
+        match std::fs::read_to_string(&image_file) {
+            Ok(digest) if digest.trim() == image_digest => {}
+            Ok(digest) => bail!(
+                "{} holds changes to image {}, not to this one {image_digest}",
+                path.display(),
+                digest.trim()
+            ),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+                if path.join("upper").exists() {
+                    bail!("{} has no image digest recorded", path.display());
+                }
+                std::fs::write(&image_file, image_digest)?;
+            }
+            Err(err) => return Err(err.into()),
+        }
+
+        for (directory, mode) in [("upper", 0o755), ("work", 0o700)] {
+            match unistd::mkdir(&path.join(directory), stat::Mode::from_bits_truncate(mode)) {
+                Ok(()) | Err(Errno::EEXIST) => {}
+                Err(err) => return Err(err.into()),
+            }
+        }
+
+        Ok(PersistDir { path, _lock: lock })
+    }
+
+    /// Where the upper and work directories are.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
@@ -0,0 +1,201 @@
+use crate::network;
+use anyhow::{bail, Context, Result};
+use log::{debug, info};
+use std::collections::HashMap;
+use std::fs::File;
+use std::net::{Ipv4Addr, Shutdown, SocketAddr, TcpListener, TcpStream, UdpSocket};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// How long a UDP client stays mapped to its container socket without traffic.
+const UDP_IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Protocol {
+    Tcp,
+    Udp,
+}
+
+impl std::fmt::Display for Protocol {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str(match self {
+            Protocol::Tcp => "tcp",
+            Protocol::Udp => "udp",
+        })
+    }
+}
+
+/// A container port published on the host, `hostport:containerport[/udp]`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PortMapping {
+    pub host_port: u16,
+    pub container_port: u16,
+    pub protocol: Protocol,
+}
+
+impl FromStr for PortMapping {
+    type Err = anyhow::Error;
+
+    fn from_str(mapping: &str) -> Result<Self> {
+        let (ports, protocol) = match mapping.split_once('/') {
+            Some((ports, "tcp")) => (ports, Protocol::Tcp),
+            Some((ports, "udp")) => (ports, Protocol::Udp),
+            Some((_, protocol)) => bail!("unknown protocol {protocol}, expected tcp or udp"),
+            None => (mapping, Protocol::Tcp),
+        };
+        let Some((host_port, container_port)) = ports.split_once(':') else {
+            bail!("expected hostport:containerport[/udp]");
+        };
+
+        Ok(PortMapping {
+            host_port: host_port.parse().context("invalid host port")?,
+            container_port: container_port.parse().context("invalid container port")?,
+            protocol,
+        })
+    }
+}
+
+/// The host side of a published port.
+pub enum Listener {
+    Tcp(TcpListener, u16),
+    Udp(UdpSocket, u16),
+}
+
+/// Binds the host side of `mappings`, up front so a taken port fails the
+/// start rather than the forwarding later.
+pub fn bind(mappings: &[PortMapping]) -> Result<Vec<Listener>> {
+    mappings
+        .iter()
+        .map(|mapping| {
+            let address = (Ipv4Addr::UNSPECIFIED, mapping.host_port);
+            info!(
+                "publishing container port {}/{} on host port {}",
+                mapping.container_port, mapping.protocol, mapping.host_port
+            );
+
+            Ok(match mapping.protocol {
+                Protocol::Tcp => Listener::Tcp(
+                    TcpListener::bind(address).with_context(|| {
+                        format!("failed binding tcp port {}", mapping.host_port)
+                    })?,
+                    mapping.container_port,
+                ),
+                Protocol::Udp => Listener::Udp(
+                    UdpSocket::bind(address).with_context(|| {
+                        format!("failed binding udp port {}", mapping.host_port)
+                    })?,
+                    mapping.container_port,
+                ),
+            })
+        })
+        .collect()
+}
+
+/// The container's port on its own loopback, so it is reached whatever
+/// address the container listens on.
+fn container_address(port: u16) -> SocketAddr {
+    (Ipv4Addr::LOCALHOST, port).into()
+}
+
+fn forward_tcp_connection(host: TcpStream, netns: &File, port: u16) -> Result<()> {
+    let container = network::in_netns(netns, || Ok(TcpStream::connect(container_address(port))?))
+        .with_context(|| format!("failed connecting to container port {port}"))?;
+
+    let copy = |mut from: TcpStream, mut to: TcpStream| {
+        move || {
+            let _ = std::io::copy(&mut from, &mut to);
+            let _ = to.shutdown(Shutdown::Write);
+        }
+    };
+    std::thread::spawn(copy(host.try_clone()?, container.try_clone()?));
+    copy(container, host)();
+
+    Ok(())
+}
+
+fn forward_tcp(listener: TcpListener, netns: Arc<File>, port: u16) {
+    for host in listener.incoming() {
+        let Ok(host) = host else {
+            continue;
+        };
+
+        let netns = netns.clone();
+        std::thread::spawn(move || {
+            if let Err(err) = forward_tcp_connection(host, &netns, port) {
+                debug!("{err:?}");
+            }
+        });
+    }
+}
+
+/// Relays datagrams of every host client through a container socket of its
+/// own, so replies find their way back.
+fn forward_udp(host: UdpSocket, netns: Arc<File>, port: u16) -> Result<()> {
+    let host = Arc::new(host);
+    let clients: Arc<Mutex<HashMap<SocketAddr, Arc<UdpSocket>>>> = Default::default();
+    let mut datagram = vec![0u8; 64 * 1024];
+
+    loop {
+        let (length, client) = host.recv_from(&mut datagram)?;
+
+        let container = clients.lock().unwrap().get(&client).cloned();
+        let container = match container {
+            Some(container) => container,
+            None => {
+                let container = match network::in_netns(&netns, || {
+                    let container = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))?;
+                    container.connect(container_address(port))?;
+                    Ok(container)
+                }) {
+                    Ok(container) => Arc::new(container),
+                    Err(err) => {
+                        debug!("failed connecting to container port {port}: {err:?}");
+                        continue;
+                    }
+                };
+                container.set_read_timeout(Some(UDP_IDLE_TIMEOUT))?;
+                clients.lock().unwrap().insert(client, container.clone());
+
+                let (host, clients, replies) = (host.clone(), clients.clone(), container.clone());
+                std::thread::spawn(move || {
+                    let mut datagram = vec![0u8; 64 * 1024];
+                    while let Ok(length) = replies.recv(&mut datagram) {
+                        let _ = host.send_to(&datagram[..length], client);
+                    }
+                    clients.lock().unwrap().remove(&client);
+                });
+
+                container
+            }
+        };
+
+        let _ = container.send(&datagram[..length]);
+    }
+}
+
+/// Forwards everything arriving on `listeners` into the container network
+/// namespace `netns`, never returns unless it fails.
+pub fn forward(listeners: Vec<Listener>, netns: File) -> Result<()> {
+    let netns = Arc::new(netns);
+
+    let forwarders: Vec<_> = listeners
+        .into_iter()
+        .map(|listener| {
+            let netns = netns.clone();
+            std::thread::spawn(move || match listener {
+                Listener::Tcp(listener, port) => {
+                    forward_tcp(listener, netns, port);
+                    Ok(())
+                }
+                Listener::Udp(socket, port) => forward_udp(socket, netns, port),
+            })
+        })
+        .collect();
+
+    for forwarder in forwarders {
+        forwarder.join().expect("port forwarding thread panicked")?;
+    }
+
+    Ok(())
+}
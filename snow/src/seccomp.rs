@@ -0,0 +1,853 @@
+use anyhow::{bail, Context, Result};
+use caps::{CapSet, Capability, CapsHashSet};
+use log::debug;
+use nix::errno::Errno;
+use nix::libc::{
+    sock_filter, sock_fprog, BPF_ABS, BPF_ALU, BPF_AND, BPF_JA, BPF_JEQ, BPF_JGE, BPF_JGT, BPF_JMP,
+    BPF_K, BPF_LD, BPF_MAXINSNS, BPF_RET, BPF_W, SECCOMP_MODE_FILTER, SECCOMP_RET_ALLOW,
+    SECCOMP_RET_DATA, SECCOMP_RET_ERRNO, SECCOMP_RET_KILL_PROCESS, SECCOMP_RET_KILL_THREAD,
+    SECCOMP_RET_LOG, SECCOMP_RET_TRACE, SECCOMP_RET_TRAP,
+};
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::str::FromStr;
+use syscalls::Sysno;
+
+/// Docker's default profile, allowing what most workloads need and what the
+/// container's capabilities grant.
+const DEFAULT_PROFILE: &str = include_str!("seccomp_default.json");
+
+/// A syscall ABI the filter tells apart, its libseccomp name, the audit arch
+/// the kernel reports for it and how it numbers syscalls.
+struct Abi {
+    name: &'static str,
+    arch: u32,
+    number: fn(&str) -> Option<i32>,
+}
+
+#[cfg(target_arch = "x86_64")]
+const ARCH_NAMES: [&str; 2] = ["amd64", "x86_64"];
+#[cfg(target_arch = "x86_64")]
+const NATIVE_ABI: Abi = Abi {
+    name: "SCMP_ARCH_X86_64",
+    arch: 0xc000_003e,
+    number: |name| Some(Sysno::from_str(name).ok()?.id()),
+};
+#[cfg(target_arch = "x86_64")]
+const SUB_ABIS: &[Abi] = &[
+    Abi {
+        name: "SCMP_ARCH_X86",
+        arch: 0x4000_0003,
+        number: |name| Some(syscalls::x86::Sysno::from_str(name).ok()?.id()),
+    },
+    Abi {
+        name: "SCMP_ARCH_X32",
+        arch: NATIVE_ABI.arch,
+        number: x32_number,
+    },
+];
+#[cfg(target_arch = "aarch64")]
+const ARCH_NAMES: [&str; 2] = ["arm64", "aarch64"];
+#[cfg(target_arch = "aarch64")]
+const NATIVE_ABI: Abi = Abi {
+    name: "SCMP_ARCH_AARCH64",
+    arch: 0xc000_00b7,
+    number: |name| Some(Sysno::from_str(name).ok()?.id()),
+};
+#[cfg(target_arch = "aarch64")]
+const SUB_ABIS: &[Abi] = &[Abi {
+    name: "SCMP_ARCH_ARM",
+    arch: 0x4000_0028,
+    number: |name| Some(syscalls::arm::Sysno::from_str(name).ok()?.id()),
+}];
+#[cfg(target_arch = "riscv64")]
+const ARCH_NAMES: [&str; 2] = ["riscv64", "riscv64"];
+#[cfg(target_arch = "riscv64")]
+const NATIVE_ABI: Abi = Abi {
+    name: "SCMP_ARCH_RISCV64",
+    arch: 0xc000_00f3,
+    number: |name| Some(Sysno::from_str(name).ok()?.id()),
+};
+#[cfg(target_arch = "riscv64")]
+const SUB_ABIS: &[Abi] = &[];
+
+/// x32 calls the x86_64 syscalls with this bit set.
+#[cfg(target_arch = "x86_64")]
+const X32_SYSCALL_BIT: i32 = 0x4000_0000;
+/// The syscalls whose structures x32 lays out differently, numbered from 512
+/// there rather than by their x86_64 numbers.
+#[cfg(target_arch = "x86_64")]
+const X32_SYSCALLS: [&str; 36] = [
+    "rt_sigaction",
+    "rt_sigreturn",
+    "ioctl",
+    "readv",
+    "writev",
+    "recvfrom",
+    "sendmsg",
+    "recvmsg",
+    "execve",
+    "ptrace",
+    "rt_sigpending",
+    "rt_sigtimedwait",
+    "rt_sigqueueinfo",
+    "sigaltstack",
+    "timer_create",
+    "mq_notify",
+    "kexec_load",
+    "waitid",
+    "set_robust_list",
+    "get_robust_list",
+    "vmsplice",
+    "move_pages",
+    "preadv",
+    "pwritev",
+    "rt_tgsigqueueinfo",
+    "recvmmsg",
+    "sendmmsg",
+    "process_vm_readv",
+    "process_vm_writev",
+    "setsockopt",
+    "getsockopt",
+    "io_setup",
+    "io_submit",
+    "execveat",
+    "preadv2",
+    "pwritev2",
+];
+
+#[cfg(target_arch = "x86_64")]
+fn x32_number(name: &str) -> Option<i32> {
+    let number = match X32_SYSCALLS.iter().position(|x32| *x32 == name) {
+        Some(position) => 512 + position as i32,
+        None => Sysno::from_str(name).ok()?.id(),
+    };
+
+    Some(number | X32_SYSCALL_BIT)
+}
+
+// Classic BPF as seccomp runs it, see linux/filter.h and linux/seccomp.h.
+const BPF_LD_W_ABS: u16 = (BPF_LD | BPF_W | BPF_ABS) as u16;
+const BPF_ALU_AND_K: u16 = (BPF_ALU | BPF_AND | BPF_K) as u16;
+const BPF_JA_K: u16 = (BPF_JMP | BPF_JA) as u16;
+const BPF_JEQ_K: u16 = (BPF_JMP | BPF_JEQ | BPF_K) as u16;
+const BPF_JGT_K: u16 = (BPF_JMP | BPF_JGT | BPF_K) as u16;
+const BPF_JGE_K: u16 = (BPF_JMP | BPF_JGE | BPF_K) as u16;
+const BPF_RET_K: u16 = (BPF_RET | BPF_K) as u16;
+
+const SECCOMP_DATA_NR: u32 = 0;
+const SECCOMP_DATA_ARCH: u32 = 4;
+const SECCOMP_DATA_ARGS: u32 = 16;
+
+#[derive(Debug, Clone, Copy, Deserialize)]
+enum Action {
+    #[serde(rename = "SCMP_ACT_KILL", alias = "SCMP_ACT_KILL_THREAD")]
+    KillThread,
+    #[serde(rename = "SCMP_ACT_KILL_PROCESS")]
+    KillProcess,
+    #[serde(rename = "SCMP_ACT_TRAP")]
+    Trap,
+    #[serde(rename = "SCMP_ACT_ERRNO")]
+    Errno,
+    #[serde(rename = "SCMP_ACT_TRACE")]
+    Trace,
+    #[serde(rename = "SCMP_ACT_LOG")]
+    Log,
+    #[serde(rename = "SCMP_ACT_ALLOW")]
+    Allow,
+}
+
+impl Action {
+    /// The filter return value, `errno` is the errno or trace message data.
+    fn ret(self, errno: Option<u32>) -> u32 {
+        let data = errno.unwrap_or(Errno::EPERM as u32) & SECCOMP_RET_DATA;
+
+        match self {
+            Action::KillThread => SECCOMP_RET_KILL_THREAD,
+            Action::KillProcess => SECCOMP_RET_KILL_PROCESS,
+            Action::Trap => SECCOMP_RET_TRAP,
+            Action::Errno => SECCOMP_RET_ERRNO | data,
+            Action::Trace => SECCOMP_RET_TRACE | data,
+            Action::Log => SECCOMP_RET_LOG,
+            Action::Allow => SECCOMP_RET_ALLOW,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Deserialize)]
+enum Operator {
+    #[serde(rename = "SCMP_CMP_NE")]
+    NotEqual,
+    #[serde(rename = "SCMP_CMP_LT")]
+    Less,
+    #[serde(rename = "SCMP_CMP_LE")]
+    LessOrEqual,
+    #[serde(rename = "SCMP_CMP_EQ")]
+    Equal,
+    #[serde(rename = "SCMP_CMP_GE")]
+    GreaterOrEqual,
+    #[serde(rename = "SCMP_CMP_GT")]
+    Greater,
+    #[serde(rename = "SCMP_CMP_MASKED_EQ")]
+    MaskedEqual,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Argument {
+    index: u32,
+    value: u64,
+    #[serde(default)]
+    value_two: u64,
+    op: Operator,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Condition {
+    #[serde(default)]
+    caps: Vec<String>,
+    #[serde(default)]
+    arches: Vec<String>,
+    min_kernel: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct SyscallRule {
+    #[serde(default)]
+    names: Vec<String>,
+    /// The single name of old profiles.
+    name: Option<String>,
+    action: Action,
+    errno_ret: Option<u32>,
+    #[serde(default)]
+    args: Vec<Argument>,
+    #[serde(default)]
+    includes: Condition,
+    #[serde(default)]
+    excludes: Condition,
+}
+
+/// An architecture and those whose binaries it also runs.
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ArchMap {
+    architecture: String,
+    #[serde(default)]
+    sub_architectures: Option<Vec<String>>,
+}
+
+/// A seccomp profile in Docker's JSON format.
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Profile {
+    default_action: Action,
+    default_errno_ret: Option<u32>,
+    /// The libseccomp names of the architectures allowed, of old profiles.
+    #[serde(default)]
+    architectures: Vec<String>,
+    #[serde(default)]
+    arch_map: Vec<ArchMap>,
+    #[serde(default)]
+    syscalls: Vec<SyscallRule>,
+}
+
+/// The profile `--seccomp` selects, none when the container runs unconfined.
+pub fn load(option: Option<&str>) -> Result<Option<Profile>> {
+    let profile = match option {
+        Some("unconfined") => return Ok(None),
+        Some(path) => std::fs::read_to_string(path)
+            .with_context(|| format!("failed reading seccomp profile {path}"))?,
+        None => DEFAULT_PROFILE.to_string(),
+    };
+
+    Ok(Some(
+        serde_json::from_str(&profile).context("invalid seccomp profile")?,
+    ))
+}
+
+/// The running kernel's major and minor version.
+fn kernel_version() -> Result<(u32, u32)> {
+    let uname = nix::sys::utsname::uname()?;
+    let release = uname.release().to_string_lossy();
+    parse_version(&release).with_context(|| format!("unexpected kernel release {release}"))
+}
+
+fn parse_version(version: &str) -> Option<(u32, u32)> {
+    let mut parts = version
+        .split(|c: char| !c.is_ascii_digit())
+        .map(str::parse::<u32>);
+    Some((parts.next()?.ok()?, parts.next()?.ok()?))
+}
+
+impl Condition {
+    fn has_cap(capabilities: &CapsHashSet, cap: &str) -> bool {
+        Capability::from_str(cap).is_ok_and(|cap| capabilities.contains(&cap))
+    }
+
+    fn has_all_caps(&self, capabilities: &CapsHashSet) -> bool {
+        self.caps
+            .iter()
+            .all(|cap| Condition::has_cap(capabilities, cap))
+    }
+
+    fn has_any_cap(&self, capabilities: &CapsHashSet) -> bool {
+        self.caps
+            .iter()
+            .any(|cap| Condition::has_cap(capabilities, cap))
+    }
+
+    fn names_arch(&self) -> bool {
+        self.arches
+            .iter()
+            .any(|arch| ARCH_NAMES.contains(&arch.as_str()))
+    }
+}
+
+impl SyscallRule {
+    /// Whether the rule applies to a container with `capabilities` on this
+    /// machine, like Docker decides it.
+    fn applies(&self, capabilities: &CapsHashSet) -> Result<bool> {
+        let includes = &self.includes;
+        if !includes.has_all_caps(capabilities)
+            || (!includes.arches.is_empty() && !includes.names_arch())
+        {
+            return Ok(false);
+        }
+        if let Some(min_kernel) = &includes.min_kernel {
+            let min_kernel = parse_version(min_kernel)
+                .with_context(|| format!("invalid minKernel {min_kernel}"))?;
+            if kernel_version()? < min_kernel {
+                return Ok(false);
+            }
+        }
+
+        let excludes = &self.excludes;
+        Ok(!excludes.has_any_cap(capabilities) && !excludes.names_arch())
+    }
+
+    fn names(&self) -> impl Iterator<Item = &str> {
+        self.names.iter().chain(&self.name).map(String::as_str)
+    }
+}
+
+fn statement(code: u16, k: u32) -> sock_filter {
+    sock_filter {
+        code,
+        jt: 0,
+        jf: 0,
+        k,
+    }
+}
+
+fn jump(code: u16, k: u32, jt: u8, jf: u8) -> sock_filter {
+    sock_filter { code, jt, jf, k }
+}
+
+/// Where a jump goes, resolved once the rule it is part of is laid out.
+#[derive(Clone, Copy)]
+enum Target {
+    Next,
+    Skip(u8),
+    Fail,
+}
+
+/// The checks of a single argument comparison, falling through when it holds.
+/// seccomp_data holds 64 bit arguments but classic BPF loads 32 bits at a time.
+fn compare(argument: &Argument) -> Vec<(u16, u32, Target, Target)> {
+    let low = SECCOMP_DATA_ARGS + 8 * argument.index;
+    let high = low + 4;
+    let split = |value: u64| ((value >> 32) as u32, value as u32);
+    let (value_high, value_low) = split(argument.value);
+    let load = |offset| (BPF_LD_W_ABS, offset, Target::Next, Target::Next);
+    use Target::*;
+
+    match argument.op {
+        Operator::Equal => vec![
+            load(high),
+            (BPF_JEQ_K, value_high, Next, Fail),
+            load(low),
+            (BPF_JEQ_K, value_low, Next, Fail),
+        ],
+        Operator::NotEqual => vec![
+            load(high),
+            (BPF_JEQ_K, value_high, Next, Skip(2)),
+            load(low),
+            (BPF_JEQ_K, value_low, Fail, Next),
+        ],
+        Operator::MaskedEqual => {
+            let (mask_high, mask_low) = (value_high, value_low);
+            let (datum_high, datum_low) = split(argument.value_two);
+            vec![
+                load(high),
+                (BPF_ALU_AND_K, mask_high, Next, Next),
+                (BPF_JEQ_K, datum_high, Next, Fail),
+                load(low),
+                (BPF_ALU_AND_K, mask_low, Next, Next),
+                (BPF_JEQ_K, datum_low, Next, Fail),
+            ]
+        }
+        Operator::Greater | Operator::GreaterOrEqual => {
+            let low_jump = match argument.op {
+                Operator::Greater => BPF_JGT_K,
+                _ => BPF_JGE_K,
+            };
+            vec![
+                load(high),
+                (BPF_JGT_K, value_high, Skip(3), Next),
+                (BPF_JEQ_K, value_high, Next, Fail),
+                load(low),
+                (low_jump, value_low, Next, Fail),
+            ]
+        }
+        Operator::Less | Operator::LessOrEqual => {
+            // The negation of Greater and GreaterOrEqual.
+            let low_jump = match argument.op {
+                Operator::Less => BPF_JGE_K,
+                _ => BPF_JGT_K,
+            };
+            vec![
+                load(high),
+                (BPF_JGT_K, value_high, Fail, Next),
+                (BPF_JEQ_K, value_high, Next, Skip(2)),
+                load(low),
+                (low_jump, value_low, Fail, Next),
+            ]
+        }
+    }
+}
+
+/// A rule with arguments, returning `ret` when all comparisons hold and
+/// falling through to whatever follows otherwise.
+fn compile_rule(arguments: &[Argument], ret: u32) -> Result<Vec<sock_filter>> {
+    let checks: Vec<_> = arguments.iter().flat_map(compare).collect();
+    let fail = checks.len() + 1;
+
+    let mut rule = vec![];
+    for (position, (code, k, jt, jf)) in checks.into_iter().enumerate() {
+        let offset = |target| -> Result<u8> {
+            let offset = match target {
+                Target::Next => 0,
+                Target::Skip(skip) => skip as usize,
+                Target::Fail => fail - position - 1,
+            };
+            u8::try_from(offset).context("seccomp rule has too many arguments")
+        };
+
+        rule.push(match code {
+            BPF_LD_W_ABS | BPF_ALU_AND_K => statement(code, k),
+            _ => jump(code, k, offset(jt)?, offset(jf)?),
+        });
+    }
+    rule.push(statement(BPF_RET_K, ret));
+
+    Ok(rule)
+}
+
+/// What the rules of a syscall come down to, the rules with arguments tried
+/// in order and what happens when none of them matches.
+#[derive(Default)]
+struct SyscallRules<'a> {
+    conditional: Vec<(&'a [Argument], u32)>,
+    unconditional: Option<u32>,
+}
+
+/// Checks the syscall number, loaded already, against each syscall with
+/// rules, `default` when none of them is it.
+fn compile_table(syscalls: BTreeMap<i32, SyscallRules>, default: u32) -> Result<Vec<sock_filter>> {
+    let mut table = vec![];
+    for (nr, rules) in syscalls {
+        let mut block = vec![];
+        for (arguments, ret) in rules.conditional {
+            block.extend(compile_rule(arguments, ret)?);
+        }
+        block.push(statement(BPF_RET_K, rules.unconditional.unwrap_or(default)));
+
+        let length = u8::try_from(block.len()).context("seccomp rules too long")?;
+        table.push(jump(BPF_JEQ_K, nr as u32, 0, length));
+        table.extend(block);
+    }
+    table.push(statement(BPF_RET_K, default));
+
+    Ok(table)
+}
+
+impl Profile {
+    /// The ABIs the filter lets through, the native one and those the profile
+    /// names besides it, like libseccomp adds them.
+    fn abis(&self) -> Vec<&'static Abi> {
+        let mut names: Vec<&str> = self.architectures.iter().map(String::as_str).collect();
+        for arch_map in &self.arch_map {
+            if arch_map.architecture == NATIVE_ABI.name {
+                names.extend(
+                    arch_map
+                        .sub_architectures
+                        .iter()
+                        .flatten()
+                        .map(String::as_str),
+                );
+            }
+        }
+
+        std::iter::once(&NATIVE_ABI)
+            .chain(SUB_ABIS.iter().filter(|abi| names.contains(&abi.name)))
+            .collect()
+    }
+
+    /// Compiles the profile for a container with `capabilities` to a filter
+    /// finding the table of the syscall's arch, then checking one syscall after
+    /// the other.
+    fn compile(&self, capabilities: &CapsHashSet) -> Result<Vec<sock_filter>> {
+        let default = self.default_action.ret(self.default_errno_ret);
+
+        let mut rules = vec![];
+        for rule in &self.syscalls {
+            if rule.applies(capabilities)? {
+                rules.push((
+                    rule,
+                    rule.action.ret(rule.errno_ret.or(self.default_errno_ret)),
+                ));
+            }
+        }
+
+        // x32 shares the x86_64 audit arch, its syscall numbers don't overlap.
+        let mut tables: BTreeMap<u32, BTreeMap<i32, SyscallRules>> = BTreeMap::new();
+        for abi in self.abis() {
+            let syscalls = tables.entry(abi.arch).or_default();
+            for &(rule, ret) in &rules {
+                for name in rule.names() {
+                    // Syscalls the ABI doesn't have.
+                    let Some(nr) = (abi.number)(name) else {
+                        continue;
+                    };
+
+                    let rules = syscalls.entry(nr).or_default();
+                    if rule.args.is_empty() {
+                        rules.unconditional.get_or_insert(ret);
+                    } else {
+                        rules.conditional.push((&rule.args, ret));
+                    }
+                }
+            }
+        }
+        let tables = tables
+            .into_iter()
+            .map(|(arch, syscalls)| Ok((arch, compile_table(syscalls, default)?)))
+            .collect::<Result<Vec<_>>>()?;
+
+        // A pair of instructions per arch jumping to its table, which are laid
+        // out after the pairs in the same order.
+        let mut program = vec![statement(BPF_LD_W_ABS, SECCOMP_DATA_ARCH)];
+        let mut preceding = 0;
+        for (position, (arch, table)) in tables.iter().enumerate() {
+            let offset = 2 * (tables.len() - position - 1) + 1 + preceding;
+            program.push(jump(BPF_JEQ_K, *arch, 0, 1));
+            program.push(statement(BPF_JA_K, offset as u32));
+            preceding += 1 + table.len();
+        }
+        // Syscalls of an architecture the profile doesn't name.
+        program.push(statement(BPF_RET_K, default));
+        for (_, table) in tables {
+            program.push(statement(BPF_LD_W_ABS, SECCOMP_DATA_NR));
+            program.extend(table);
+        }
+
+        if program.len() > BPF_MAXINSNS as usize {
+            bail!("seccomp profile compiles to too large a filter");
+        }
+
+        Ok(program)
+    }
+
+    /// Confines the calling process for good, which needs no_new_privs set
+    /// unless it has CAP_SYS_ADMIN.
+    pub fn install(&self) -> Result<()> {
+        let capabilities = caps::read(None, CapSet::Bounding)?;
+        let mut program = self.compile(&capabilities)?;
+        debug!(
+            "installing seccomp filter of {} instructions",
+            program.len()
+        );
+
+        let filter = sock_fprog {
+            len: program.len() as u16,
+            filter: program.as_mut_ptr(),
+        };
+        // SAFETY: the filter points at the program which outlives the call.
+        Errno::result(unsafe {
+            nix::libc::prctl(nix::libc::PR_SET_SECCOMP, SECCOMP_MODE_FILTER, &filter)
+        })?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ALLOW: u32 = SECCOMP_RET_ALLOW;
+    const DENY: u32 = SECCOMP_RET_ERRNO | Errno::EPERM as u32;
+
+    /// Values around where the comparisons split their operands in two.
+    const VALUES: [u64; 11] = [
+        0,
+        4,
+        5,
+        6,
+        0xffff_ffff,
+        0x1_0000_0000,
+        0x1_0000_0004,
+        0x1_0000_0005,
+        0x1_0000_0006,
+        0x5_0000_0000,
+        u64::MAX,
+    ];
+
+    /// Runs `program` on a syscall the way seccomp does.
+    fn run(program: &[sock_filter], arch: u32, nr: i32, args: [u64; 6]) -> u32 {
+        let mut data = vec![];
+        data.extend(nr.to_ne_bytes());
+        data.extend(arch.to_ne_bytes());
+        // The instruction pointer.
+        data.extend(0u64.to_ne_bytes());
+        for arg in args {
+            data.extend(arg.to_ne_bytes());
+        }
+
+        let mut accumulator = 0;
+        let mut pc = 0;
+        loop {
+            let instruction = program[pc];
+            let k = instruction.k;
+            let branch = |taken: bool| match taken {
+                true => instruction.jt as usize,
+                false => instruction.jf as usize,
+            };
+            pc += 1;
+
+            match instruction.code {
+                BPF_LD_W_ABS => {
+                    let word = &data[k as usize..k as usize + 4];
+                    accumulator = u32::from_ne_bytes(word.try_into().unwrap());
+                }
+                BPF_ALU_AND_K => accumulator &= k,
+                BPF_JA_K => pc += k as usize,
+                BPF_JEQ_K => pc += branch(accumulator == k),
+                BPF_JGT_K => pc += branch(accumulator > k),
+                BPF_JGE_K => pc += branch(accumulator >= k),
+                BPF_RET_K => return k,
+                code => panic!("unexpected instruction {code:#x}"),
+            }
+        }
+    }
+
+    /// What the filter should make of comparing `arg` with `argument`.
+    fn holds(argument: &Argument, arg: u64) -> bool {
+        let value = argument.value;
+        match argument.op {
+            Operator::NotEqual => arg != value,
+            Operator::Less => arg < value,
+            Operator::LessOrEqual => arg <= value,
+            Operator::Equal => arg == value,
+            Operator::GreaterOrEqual => arg >= value,
+            Operator::Greater => arg > value,
+            Operator::MaskedEqual => arg & value == argument.value_two,
+        }
+    }
+
+    fn rule(arguments: &[Argument]) -> Vec<sock_filter> {
+        let mut program = compile_rule(arguments, ALLOW).unwrap();
+        program.push(statement(BPF_RET_K, DENY));
+        program
+    }
+
+    fn compile(profile: &str) -> Vec<sock_filter> {
+        let profile: Profile = serde_json::from_str(profile).unwrap();
+        profile.compile(&CapsHashSet::new()).unwrap()
+    }
+
+    #[test]
+    fn operators() {
+        for op in [
+            Operator::NotEqual,
+            Operator::Less,
+            Operator::LessOrEqual,
+            Operator::Equal,
+            Operator::GreaterOrEqual,
+            Operator::Greater,
+            Operator::MaskedEqual,
+        ] {
+            for value in VALUES {
+                for value_two in [0, 4, 0x1_0000_0004] {
+                    let argument = Argument {
+                        index: 2,
+                        value,
+                        value_two,
+                        op,
+                    };
+                    let program = rule(std::slice::from_ref(&argument));
+
+                    for arg in VALUES {
+                        // The arguments next to it set, loading those shows.
+                        let args = [0, u64::MAX, arg, u64::MAX, 0, 0];
+                        assert_eq!(
+                            run(&program, NATIVE_ABI.arch, 0, args) == ALLOW,
+                            holds(&argument, arg),
+                            "{argument:?} on {arg:#x}"
+                        );
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn rule_needs_all_arguments() {
+        let arguments = [
+            Argument {
+                index: 0,
+                value: 5,
+                value_two: 0,
+                op: Operator::Greater,
+            },
+            Argument {
+                index: 1,
+                value: 0xff,
+                value_two: 0x10,
+                op: Operator::MaskedEqual,
+            },
+            Argument {
+                index: 5,
+                value: 0x1_0000_0000,
+                value_two: 0,
+                op: Operator::LessOrEqual,
+            },
+        ];
+        let program = rule(&arguments);
+
+        for first in VALUES {
+            for second in [0, 0x10, 0x11, 0x110, 0x1_0000_0010] {
+                for last in VALUES {
+                    let args = [first, second, 0, 0, 0, last];
+                    let expected = arguments
+                        .iter()
+                        .all(|argument| holds(argument, args[argument.index as usize]));
+                    assert_eq!(
+                        run(&program, NATIVE_ABI.arch, 0, args) == ALLOW,
+                        expected,
+                        "{args:x?}"
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn rules_in_order() {
+        let program = compile(
+            r#"{
+                "defaultAction": "SCMP_ACT_ERRNO",
+                "syscalls": [
+                    {
+                        "names": ["personality"],
+                        "action": "SCMP_ACT_ALLOW",
+                        "args": [{"index": 0, "value": 0, "op": "SCMP_CMP_EQ"}]
+                    },
+                    {
+                        "names": ["personality"],
+                        "action": "SCMP_ACT_ALLOW",
+                        "args": [{"index": 0, "value": 8, "op": "SCMP_CMP_EQ"}]
+                    },
+                    {"names": ["getpid", "no_such_syscall"], "action": "SCMP_ACT_ALLOW"},
+                    {"names": ["getpid", "kill"], "action": "SCMP_ACT_ERRNO", "errnoRet": 38}
+                ]
+            }"#,
+        );
+        let syscall = |sysno: Sysno, arg| run(&program, NATIVE_ABI.arch, sysno.id(), [arg; 6]);
+
+        assert_eq!(syscall(Sysno::personality, 0), ALLOW);
+        assert_eq!(syscall(Sysno::personality, 8), ALLOW);
+        assert_eq!(syscall(Sysno::personality, 1), DENY);
+        assert_eq!(syscall(Sysno::getpid, 0), ALLOW);
+        assert_eq!(syscall(Sysno::kill, 0), SECCOMP_RET_ERRNO | 38);
+        assert_eq!(syscall(Sysno::read, 0), DENY);
+    }
+
+    #[test]
+    #[cfg(target_arch = "x86_64")]
+    fn sub_architectures() {
+        const I386: u32 = 0x4000_0003;
+        let rules = r#"[{"names": ["getpid", "recvmsg"], "action": "SCMP_ACT_ALLOW"}]"#;
+        let mapped = compile(&format!(
+            r#"{{
+                "defaultAction": "SCMP_ACT_ERRNO",
+                "archMap": [
+                    {{
+                        "architecture": "SCMP_ARCH_X86_64",
+                        "subArchitectures": ["SCMP_ARCH_X86", "SCMP_ARCH_X32"]
+                    }},
+                    {{"architecture": "SCMP_ARCH_AARCH64", "subArchitectures": ["SCMP_ARCH_ARM"]}}
+                ],
+                "syscalls": {rules}
+            }}"#
+        ));
+        let native_only = compile(&format!(
+            r#"{{"defaultAction": "SCMP_ACT_ERRNO", "syscalls": {rules}}}"#
+        ));
+        let x86 = |sysno: syscalls::x86::Sysno| sysno.id();
+        let x32 = |nr: i32| nr | X32_SYSCALL_BIT;
+
+        for (arch, nr, allowed) in [
+            (NATIVE_ABI.arch, Sysno::getpid.id(), true),
+            (NATIVE_ABI.arch, Sysno::recvmsg.id(), true),
+            (NATIVE_ABI.arch, Sysno::read.id(), false),
+            (I386, x86(syscalls::x86::Sysno::getpid), true),
+            (I386, x86(syscalls::x86::Sysno::recvmsg), true),
+            (I386, Sysno::getpid.id(), false),
+            (NATIVE_ABI.arch, x32(Sysno::getpid.id()), true),
+            // recvmsg of x32.
+            (NATIVE_ABI.arch, x32(519), true),
+            // recvmsg by its x86_64 number, which x32 doesn't have.
+            (NATIVE_ABI.arch, x32(Sysno::recvmsg.id()), false),
+            // Not a sub-architecture of this one.
+            (0xc000_00b7, Sysno::getpid.id(), false),
+        ] {
+            let expected = if allowed { ALLOW } else { DENY };
+            assert_eq!(
+                run(&mapped, arch, nr, [0; 6]),
+                expected,
+                "{arch:#x} {nr:#x}"
+            );
+        }
+
+        // Without the sub-architectures only x86_64 syscalls are allowed.
+        for (arch, nr) in [
+            (I386, x86(syscalls::x86::Sysno::getpid)),
+            (NATIVE_ABI.arch, x32(Sysno::getpid.id())),
+        ] {
+            assert_eq!(
+                run(&native_only, arch, nr, [0; 6]),
+                DENY,
+                "{arch:#x} {nr:#x}"
+            );
+        }
+    }
+
+    #[test]
+    fn default_profile_compiles() {
+        let profile = load(None).unwrap().unwrap();
+        let program = profile.compile(&CapsHashSet::new()).unwrap();
+
+        assert_eq!(
+            run(&program, NATIVE_ABI.arch, Sysno::read.id(), [0; 6]),
+            ALLOW
+        );
+        // Needs CAP_SYS_ADMIN, which the container lacks.
+        assert_eq!(
+            run(&program, NATIVE_ABI.arch, Sysno::mount.id(), [0; 6]),
+            DENY
+        );
+    }
+}
@@ -0,0 +1,159 @@
+use crate::payload;
+use anyhow::{bail, Context, Result};
+use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey};
+use log::{info, warn};
+use std::fs::{File, OpenOptions};
+use std::io::prelude::*;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::Path;
+
+// The signature covers the metadata section, which records the digest of the
+// image, so checking the digest afterwards ties the signature to the image bytes.
+const SIGNATURE_CONTEXT: &[u8] = b"snow image signature v1\0";
+
+// A hex encoded public key baked into the runtime at build time, every image
+// this runtime is stamped with must then be signed by its private key.
+const COMPILED_TRUSTED_KEY: Option<&str> = option_env!("SNOW_TRUSTED_PUBLIC_KEY");
+
+fn signed_message(metadata: &[u8]) -> Vec<u8> {
+    [SIGNATURE_CONTEXT, metadata].concat()
+}
+
+fn parse_hex_key(hex_key: &str) -> Result<[u8; 32]> {
+    let bytes = hex::decode(hex_key.trim())?;
+
+    bytes.try_into().map_err(|bytes: Vec<u8>| {
+        anyhow::anyhow!("expected a 32 byte key, got {} bytes", bytes.len())
+    })
+}
+
+pub fn read_signing_key(path: &Path) -> Result<SigningKey> {
+    let hex_key = std::fs::read_to_string(path)
+        .with_context(|| format!("failed reading signing key {}", path.display()))?;
+
+    Ok(SigningKey::from_bytes(&parse_hex_key(&hex_key)?))
+}
+
+pub fn read_verifying_key(path: &Path) -> Result<VerifyingKey> {
+    let hex_key = std::fs::read_to_string(path)
+        .with_context(|| format!("failed reading public key {}", path.display()))?;
+
+    Ok(VerifyingKey::from_bytes(&parse_hex_key(&hex_key)?)?)
+}
+
+/// The keys images are checked against, the one compiled into the runtime and
+/// the one given at runtime.
+pub fn trusted_keys(runtime_key_path: Option<&Path>) -> Result<Vec<VerifyingKey>> {
+    let mut keys = Vec::new();
+
+    if let Some(hex_key) = COMPILED_TRUSTED_KEY {
+        keys.push(VerifyingKey::from_bytes(&parse_hex_key(hex_key)?)?);
+    }
+
+    if let Some(path) = runtime_key_path {
+        keys.push(read_verifying_key(path)?);
+    }
+
+    Ok(keys)
+}
+
+pub fn sign(signing_key: &SigningKey, metadata: &[u8]) -> [u8; 64] {
+    signing_key.sign(&signed_message(metadata)).to_bytes()
+}
+
+pub fn verify(trusted_keys: &[VerifyingKey], metadata: &[u8], signature: &[u8]) -> Result<()> {
+    let signature = Signature::from_slice(signature).context("malformed image signature")?;
+    let message = signed_message(metadata);
+
+    if !trusted_keys
+        .iter()
+        .any(|key| key.verify(&message, &signature).is_ok())
+    {
+        bail!("image signature was not made by a trusted key");
+    }
+
+    Ok(())
+}
+
+/// Writes a new hex encoded key pair, the private key is only readable by its owner.
+pub fn generate_keypair(secret_key_path: &Path, public_key_path: &Path) -> Result<()> {
+    let signing_key = SigningKey::generate(&mut rand::rngs::OsRng);
+
+    let mut secret_key_file = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(secret_key_path)
+        .with_context(|| format!("failed creating {}", secret_key_path.display()))?;
+    writeln!(secret_key_file, "{}", hex::encode(signing_key.to_bytes()))?;
+
+    std::fs::write(
+        public_key_path,
+        format!("{}\n", hex::encode(signing_key.verifying_key().to_bytes())),
+    )?;
+
+    Ok(())
+}
+
+/// Checks the image `self_exe` carries against the digests recorded when it
+/// was packed, and its signature against the trusted keys.
+pub fn verify_image(
+    payload: &payload::Payload,
+    self_exe: &mut File,
+    skip_digest_check: bool,
+    trusted_key: Option<&Path>,
+) -> Result<()> {
+    let trusted_keys = trusted_keys(trusted_key)?;
+
+    if skip_digest_check {
+        if trusted_keys.is_empty() {
+            warn!("skipping image digest check");
+            return Ok(());
+        }
+
+        // The signature only means something together with the digest.
+        warn!("a trusted key is configured, not skipping the image digest check");
+    }
+
+    let Some(metadata) = payload.read_section(self_exe, payload::METADATA_SECTION)? else {
+        bail!("image has no recorded digest, repack it or pass --skip-digest-check");
+    };
+
+    if !trusted_keys.is_empty() {
+        info!("verifying image signature");
+        let Some(image_signature) = payload.read_section(self_exe, payload::SIGNATURE_SECTION)?
+        else {
+            bail!("image is not signed but a trusted key is configured");
+        };
+        verify(&trusted_keys, &metadata, &image_signature)?;
+    } else if payload.section(payload::SIGNATURE_SECTION).is_some() {
+        info!("image is signed but no trusted key is configured, ignoring the signature");
+    }
+
+    info!("verifying image digest");
+    let metadata: payload::Metadata = serde_json::from_slice(&metadata)?;
+
+    let squashfs = payload
+        .section(payload::SQUASHFS_SECTION)
+        .expect("squashfs section not found");
+    let digest = payload::sha256(payload::section_reader(self_exe, squashfs)?)?;
+
+    if digest != metadata.squashfs_sha256 {
+        bail!(
+            "image digest mismatch, expected {} but found {}, the binary is probably corrupted",
+            metadata.squashfs_sha256,
+            digest
+        );
+    }
+
+    // The config decides what runs in the container so it has to be covered too.
+    let image_config = payload.read_section(self_exe, payload::CONFIG_SECTION)?;
+    match (image_config, &metadata.config_sha256) {
+        (Some(image_config), Some(expected))
+            if payload::sha256(image_config.as_slice())? == *expected => {}
+        (None, None) => {}
+        _ => bail!("image config does not match its recorded digest"),
+    }
+
+    Ok(())
+}
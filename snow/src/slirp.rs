@@ -0,0 +1,782 @@
+use anyhow::Result;
+use log::debug;
+use nix::errno::Errno;
+use nix::fcntl::OFlag;
+use nix::poll::{poll, PollFd, PollFlags, PollTimeout};
+use nix::sys::socket::{connect, socket, AddressFamily, SockFlag, SockType, SockaddrStorage};
+use smoltcp::iface::{Config, Interface, SocketHandle, SocketSet};
+use smoltcp::phy::{Device, DeviceCapabilities, Medium, RxToken, TxToken};
+use smoltcp::socket::{tcp, udp};
+use smoltcp::wire::{
+    EthernetAddress, EthernetFrame, EthernetProtocol, IpAddress, IpCidr, IpEndpoint,
+    IpListenEndpoint, IpProtocol, Ipv4Packet, TcpPacket, UdpPacket,
+};
+use std::collections::hash_map::Entry;
+use std::collections::{HashMap, VecDeque};
+use std::fs::File;
+use std::io::prelude::*;
+use std::io::ErrorKind;
+use std::net::{Ipv4Addr, Shutdown, SocketAddr, SocketAddrV4, TcpStream, UdpSocket};
+use std::os::fd::{AsFd, AsRawFd};
+use std::os::unix::fs::OpenOptionsExt;
+use std::time::{Duration, Instant};
+
+// The addresses QEMU's slirp uses, we are the gateway and the nameserver and
+// the container gets the rest of the network to itself.
+pub const GATEWAY: Ipv4Addr = Ipv4Addr::new(10, 0, 2, 2);
+pub const NAMESERVER: Ipv4Addr = Ipv4Addr::new(10, 0, 2, 3);
+pub const CONTAINER_ADDRESS: Ipv4Addr = Ipv4Addr::new(10, 0, 2, 100);
+pub const PREFIX_LENGTH: u8 = 24;
+
+const MTU: usize = 1500;
+const ETHERNET_HEADER_SIZE: usize = 14;
+const GATEWAY_MAC: EthernetAddress = EthernetAddress([0x52, 0x55, 0x0a, 0x00, 0x02, 0x02]);
+const TCP_BUFFER_SIZE: usize = 64 * 1024;
+const UDP_BUFFER_SIZE: usize = 64 * 1024;
+const UDP_BUFFER_PACKETS: usize = 64;
+const UDP_IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+
+#[repr(C)]
+struct IfReq {
+    name: [u8; 16],
+    flags: i16,
+    padding: [u8; 22],
+}
+
+const IFF_TAP: i16 = 0x0002;
+const IFF_NO_PI: i16 = 0x1000;
+
+nix::ioctl_write_ptr_bad!(
+    tunsetiff,
+    nix::request_code_write!(b'T', 202, std::mem::size_of::<i32>()),
+    IfReq
+);
+
+/// Creates the TAP device `name` in the network namespace of the calling
+/// thread, the frames the container sends come out of the returned file.
+pub fn create_tap(name: &str) -> Result<File> {
+    let tap = File::options()
+        .read(true)
+        .write(true)
+        .custom_flags(OFlag::O_NONBLOCK.bits())
+        .open("/dev/net/tun")?;
+
+    let mut request = IfReq {
+        name: [0; 16],
+        flags: IFF_TAP | IFF_NO_PI,
+        padding: [0; 22],
+    };
+    request.name[..name.len()].copy_from_slice(name.as_bytes());
+
+    // SAFETY: the request is a properly laid out ifreq.
+    unsafe { tunsetiff(tap.as_raw_fd(), &request) }?;
+
+    Ok(tap)
+}
+
+/// The TAP device as smoltcp sees it, frames are read by `Slirp` so it can
+/// look at them first.
+struct TapDevice {
+    tap: File,
+    received: VecDeque<Vec<u8>>,
+}
+
+struct TapRxToken(Vec<u8>);
+
+impl RxToken for TapRxToken {
+    fn consume<R, F>(self, f: F) -> R
+    where
+        F: FnOnce(&[u8]) -> R,
+    {
+        f(&self.0)
+    }
+}
+
+struct TapTxToken<'a>(&'a File);
+
+impl TxToken for TapTxToken<'_> {
+    fn consume<R, F>(self, len: usize, f: F) -> R
+    where
+        F: FnOnce(&mut [u8]) -> R,
+    {
+        let mut frame = vec![0; len];
+        let result = f(&mut frame);
+        // A full TAP queue drops the frame, just like a real link would.
+        let _ = (&*self.0).write(&frame);
+
+        result
+    }
+}
+
+impl Device for TapDevice {
+    type RxToken<'a> = TapRxToken;
+    type TxToken<'a> = TapTxToken<'a>;
+
+    fn receive(
+        &mut self,
+        _timestamp: smoltcp::time::Instant,
+    ) -> Option<(Self::RxToken<'_>, Self::TxToken<'_>)> {
+        let frame = self.received.pop_front()?;
+        Some((TapRxToken(frame), TapTxToken(&self.tap)))
+    }
+
+    fn transmit(&mut self, _timestamp: smoltcp::time::Instant) -> Option<Self::TxToken<'_>> {
+        Some(TapTxToken(&self.tap))
+    }
+
+    fn capabilities(&self) -> DeviceCapabilities {
+        let mut capabilities = DeviceCapabilities::default();
+        capabilities.medium = Medium::Ethernet;
+        capabilities.max_transmission_unit = MTU + ETHERNET_HEADER_SIZE;
+
+        capabilities
+    }
+}
+
+/// A connection or datagram exchange between an endpoint of the container and
+/// a destination as the container addresses it.
+type Flow = (SocketAddrV4, SocketAddrV4);
+
+struct TcpFlow {
+    handle: SocketHandle,
+    host: TcpStream,
+    connecting: bool,
+    host_write_shut_down: bool,
+    host_eof: bool,
+}
+
+struct UdpFlow {
+    host: UdpSocket,
+    last_used: Instant,
+}
+
+/// Where we connect on the host for `destination`. The gateway stands for
+/// the host loopback and the rest of the network is only the container's.
+fn host_destination(nameserver: SocketAddr, destination: SocketAddrV4) -> Option<SocketAddr> {
+    let network_mask = u32::MAX << (32 - PREFIX_LENGTH);
+
+    match *destination.ip() {
+        GATEWAY => Some(SocketAddr::from((Ipv4Addr::LOCALHOST, destination.port()))),
+        NAMESERVER if destination.port() == 53 => Some(nameserver),
+        address if u32::from(address) & network_mask == u32::from(GATEWAY) & network_mask => None,
+        _ => Some(destination.into()),
+    }
+}
+
+fn connect_nonblocking(address: SocketAddr) -> Result<TcpStream> {
+    let family = match address {
+        SocketAddr::V4(_) => AddressFamily::Inet,
+        SocketAddr::V6(_) => AddressFamily::Inet6,
+    };
+    let socket = socket(
+        family,
+        SockType::Stream,
+        SockFlag::SOCK_NONBLOCK | SockFlag::SOCK_CLOEXEC,
+        None,
+    )?;
+
+    match connect(socket.as_raw_fd(), &SockaddrStorage::from(address)) {
+        Ok(()) | Err(Errno::EINPROGRESS) => Ok(TcpStream::from(socket)),
+        Err(err) => Err(err.into()),
+    }
+}
+
+fn connect_udp(address: SocketAddr) -> Result<UdpSocket> {
+    let unspecified: SocketAddr = match address {
+        SocketAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+        SocketAddr::V6(_) => (std::net::Ipv6Addr::UNSPECIFIED, 0).into(),
+    };
+    let host = UdpSocket::bind(unspecified)?;
+    host.connect(address)?;
+    host.set_nonblocking(true)?;
+
+    Ok(host)
+}
+
+fn endpoint(address: SocketAddrV4) -> IpEndpoint {
+    IpEndpoint::new(IpAddress::Ipv4(*address.ip()), address.port())
+}
+
+/// A userspace NAT between the container network behind a TAP device and
+/// sockets on the host, for when we may not touch the host network.
+///
+/// smoltcp terminates the container's TCP connections and UDP exchanges, each
+/// of which we mirror with a host socket to wherever the container meant.
+pub struct Slirp {
+    device: TapDevice,
+    iface: Interface,
+    sockets: SocketSet<'static>,
+    tcp_flows: HashMap<Flow, TcpFlow>,
+    udp_sockets: HashMap<SocketAddrV4, SocketHandle>,
+    udp_flows: HashMap<Flow, UdpFlow>,
+    nameserver: SocketAddr,
+}
+
+impl Slirp {
+    pub fn new(tap: File, nameserver: SocketAddr) -> Self {
+        let mut device = TapDevice {
+            tap,
+            received: VecDeque::new(),
+        };
+
+        let mut config = Config::new(GATEWAY_MAC.into());
+        config.random_seed = rand::random();
+
+        let mut iface = Interface::new(config, &mut device, smoltcp::time::Instant::now());
+        iface.update_ip_addrs(|addresses| {
+            for address in [GATEWAY, NAMESERVER] {
+                addresses
+                    .push(IpCidr::new(IpAddress::Ipv4(address), PREFIX_LENGTH))
+                    .expect("too many interface addresses");
+            }
+        });
+        // Everything the container sends our way is ours to answer.
+        iface.set_any_ip(true);
+
+        Slirp {
+            device,
+            iface,
+            sockets: SocketSet::new(Vec::new()),
+            tcp_flows: HashMap::new(),
+            udp_sockets: HashMap::new(),
+            udp_flows: HashMap::new(),
+            nameserver,
+        }
+    }
+
+    /// Gets a socket ready for what `frame` starts before smoltcp sees it, it
+    /// would reset the connection or drop the datagram otherwise.
+    fn inspect(&mut self, frame: &[u8]) {
+        let Ok(ethernet) = EthernetFrame::new_checked(frame) else {
+            return;
+        };
+        if ethernet.ethertype() != EthernetProtocol::Ipv4 {
+            return;
+        }
+        let Ok(ip) = Ipv4Packet::new_checked(ethernet.payload()) else {
+            return;
+        };
+
+        match ip.next_header() {
+            IpProtocol::Tcp => {
+                let Ok(tcp) = TcpPacket::new_checked(ip.payload()) else {
+                    return;
+                };
+                if tcp.syn() && !tcp.ack() {
+                    self.open_tcp_flow((
+                        SocketAddrV4::new(ip.src_addr(), tcp.src_port()),
+                        SocketAddrV4::new(ip.dst_addr(), tcp.dst_port()),
+                    ));
+                }
+            }
+            IpProtocol::Udp => {
+                let Ok(udp) = UdpPacket::new_checked(ip.payload()) else {
+                    return;
+                };
+                self.open_udp_socket(SocketAddrV4::new(ip.dst_addr(), udp.dst_port()));
+            }
+            _ => {}
+        }
+    }
+
+    fn open_tcp_flow(&mut self, flow: Flow) {
+        // A retransmitted SYN.
+        if self.tcp_flows.contains_key(&flow) {
+            return;
+        }
+
+        let Some(destination) = host_destination(self.nameserver, flow.1) else {
+            return;
+        };
+        let host = match connect_nonblocking(destination) {
+            Ok(host) => host,
+            Err(err) => {
+                debug!("failed connecting to {destination}: {err}");
+                return;
+            }
+        };
+
+        let mut socket = tcp::Socket::new(
+            tcp::SocketBuffer::new(vec![0; TCP_BUFFER_SIZE]),
+            tcp::SocketBuffer::new(vec![0; TCP_BUFFER_SIZE]),
+        );
+        let listen_endpoint = IpListenEndpoint {
+            addr: Some(IpAddress::Ipv4(*flow.1.ip())),
+            port: flow.1.port(),
+        };
+        if socket.listen(listen_endpoint).is_err() {
+            return;
+        }
+
+        debug!("tcp {} -> {} via {destination}", flow.0, flow.1);
+        self.tcp_flows.insert(
+            flow,
+            TcpFlow {
+                handle: self.sockets.add(socket),
+                host,
+                connecting: true,
+                host_write_shut_down: false,
+                host_eof: false,
+            },
+        );
+    }
+
+    fn open_udp_socket(&mut self, destination: SocketAddrV4) {
+        if self.udp_sockets.contains_key(&destination)
+            || host_destination(self.nameserver, destination).is_none()
+        {
+            return;
+        }
+
+        let buffer = || {
+            udp::PacketBuffer::new(
+                vec![udp::PacketMetadata::EMPTY; UDP_BUFFER_PACKETS],
+                vec![0; UDP_BUFFER_SIZE],
+            )
+        };
+        let mut socket = udp::Socket::new(buffer(), buffer());
+        if socket.bind(endpoint(destination)).is_err() {
+            return;
+        }
+
+        self.udp_sockets
+            .insert(destination, self.sockets.add(socket));
+    }
+
+    /// Moves data between a TCP flow and its host socket, returns whether the
+    /// flow is still alive.
+    fn pump_tcp_flow(socket: &mut tcp::Socket, flow: &mut TcpFlow) -> bool {
+        if flow.connecting {
+            match flow.host.take_error() {
+                Ok(None) => {}
+                Ok(Some(err)) | Err(err) => {
+                    debug!("failed connecting: {err}");
+                    socket.abort();
+                    return false;
+                }
+            }
+
+            if flow.host.peer_addr().is_err() {
+                return socket.state() != tcp::State::Closed;
+            }
+            flow.connecting = false;
+        }
+
+        while socket.can_recv() {
+            let written = socket.recv(|data| match (&flow.host).write(data) {
+                Ok(written) => (written, Ok(written)),
+                Err(err) => (0, Err(err)),
+            });
+
+            match written {
+                Ok(Ok(written)) if written > 0 => {}
+                Ok(Err(err)) if err.kind() != ErrorKind::WouldBlock => {
+                    socket.abort();
+                    return false;
+                }
+                _ => break,
+            }
+        }
+
+        // The container closed its side and we passed on all it sent.
+        let container_done = matches!(
+            socket.state(),
+            tcp::State::CloseWait | tcp::State::LastAck | tcp::State::Closing
+        );
+        if container_done && socket.recv_queue() == 0 && !flow.host_write_shut_down {
+            let _ = flow.host.shutdown(Shutdown::Write);
+            flow.host_write_shut_down = true;
+        }
+
+        while !flow.host_eof && socket.can_send() {
+            let read = socket.send(|buffer| match (&flow.host).read(buffer) {
+                Ok(read) => (read, Ok(read)),
+                Err(err) => (0, Err(err)),
+            });
+
+            match read {
+                Ok(Ok(0)) => {
+                    flow.host_eof = true;
+                    socket.close();
+                }
+                Ok(Ok(_)) => {}
+                Ok(Err(err)) if err.kind() == ErrorKind::WouldBlock => break,
+                Ok(Err(_)) => {
+                    socket.abort();
+                    return false;
+                }
+                Err(_) => break,
+            }
+        }
+
+        !matches!(socket.state(), tcp::State::Closed | tcp::State::TimeWait)
+    }
+
+    fn pump_tcp(&mut self) {
+        let Slirp {
+            sockets, tcp_flows, ..
+        } = self;
+
+        tcp_flows.retain(|_, flow| {
+            let alive = Slirp::pump_tcp_flow(sockets.get_mut::<tcp::Socket>(flow.handle), flow);
+            if !alive {
+                sockets.remove(flow.handle);
+            }
+
+            alive
+        });
+    }
+
+    fn pump_udp(&mut self) {
+        let Slirp {
+            sockets,
+            udp_sockets,
+            udp_flows,
+            nameserver,
+            ..
+        } = self;
+        let now = Instant::now();
+
+        for (&destination, &handle) in udp_sockets.iter() {
+            let socket = sockets.get_mut::<udp::Socket>(handle);
+
+            while let Ok((data, metadata)) = socket.recv() {
+                let IpEndpoint {
+                    addr: IpAddress::Ipv4(address),
+                    port,
+                } = metadata.endpoint;
+                let flow = (SocketAddrV4::new(address, port), destination);
+
+                let udp_flow = match udp_flows.entry(flow) {
+                    Entry::Occupied(entry) => entry.into_mut(),
+                    Entry::Vacant(entry) => {
+                        let Some(host_destination) = host_destination(*nameserver, destination)
+                        else {
+                            continue;
+                        };
+                        match connect_udp(host_destination) {
+                            Ok(host) => {
+                                debug!("udp {} -> {} via {host_destination}", flow.0, flow.1);
+                                entry.insert(UdpFlow {
+                                    host,
+                                    last_used: now,
+                                })
+                            }
+                            Err(err) => {
+                                debug!("failed connecting to {host_destination}: {err}");
+                                continue;
+                            }
+                        }
+                    }
+                };
+                udp_flow.last_used = now;
+                let _ = udp_flow.host.send(data);
+            }
+        }
+
+        let mut datagram = vec![0u8; UDP_BUFFER_SIZE];
+        for ((source, destination), udp_flow) in udp_flows.iter_mut() {
+            let socket = sockets.get_mut::<udp::Socket>(udp_sockets[destination]);
+
+            while socket.can_send() {
+                let Ok(length) = udp_flow.host.recv(&mut datagram) else {
+                    break;
+                };
+                udp_flow.last_used = now;
+                let _ = socket.send_slice(&datagram[..length], endpoint(*source));
+            }
+        }
+
+        udp_flows.retain(|_, udp_flow| now - udp_flow.last_used < UDP_IDLE_TIMEOUT);
+        udp_sockets.retain(|destination, handle| {
+            let used = udp_flows
+                .keys()
+                .any(|(_, flow_destination)| flow_destination == destination);
+            if !used {
+                sockets.remove(*handle);
+            }
+
+            used
+        });
+    }
+
+    /// Sleeps until the container or a host socket has something for us or
+    /// smoltcp has timers to run.
+    fn wait(&mut self) -> Result<()> {
+        let now = smoltcp::time::Instant::now();
+        let delay = self
+            .iface
+            .poll_delay(now, &self.sockets)
+            .map_or(1000, |delay| delay.total_millis().min(1000)) as u16;
+
+        let mut fds = vec![PollFd::new(self.device.tap.as_fd(), PollFlags::POLLIN)];
+        for flow in self.tcp_flows.values() {
+            let socket = self.sockets.get::<tcp::Socket>(flow.handle);
+
+            let mut events = PollFlags::empty();
+            if flow.connecting || socket.can_recv() {
+                events |= PollFlags::POLLOUT;
+            }
+            if !flow.connecting && !flow.host_eof && socket.can_send() {
+                events |= PollFlags::POLLIN;
+            }
+            fds.push(PollFd::new(flow.host.as_fd(), events));
+        }
+        for flow in self.udp_flows.values() {
+            fds.push(PollFd::new(flow.host.as_fd(), PollFlags::POLLIN));
+        }
+
+        match poll(&mut fds, PollTimeout::from(delay)) {
+            Ok(_) | Err(Errno::EINTR) => Ok(()),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    pub fn run(mut self) -> Result<()> {
+        let mut frame = vec![0u8; 65536];
+
+        loop {
+            loop {
+                match (&self.device.tap).read(&mut frame) {
+                    Ok(length) => {
+                        self.inspect(&frame[..length]);
+                        self.device.received.push_back(frame[..length].to_vec());
+                    }
+                    Err(err) if err.kind() == ErrorKind::WouldBlock => break,
+                    Err(err) => return Err(err.into()),
+                }
+            }
+
+            let now = smoltcp::time::Instant::now();
+            self.iface.poll(now, &mut self.device, &mut self.sockets);
+            self.pump_tcp();
+            self.pump_udp();
+            self.iface.poll(now, &mut self.device, &mut self.sockets);
+
+            self.wait()?;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nix::sys::socket::socketpair;
+    use std::net::TcpListener;
+
+    const CONTAINER_MAC: EthernetAddress = EthernetAddress([0x52, 0x55, 0x0a, 0x00, 0x02, 0x64]);
+
+    /// The container end of the link, a smoltcp interface of its own.
+    struct Container {
+        device: TapDevice,
+        iface: Interface,
+        sockets: SocketSet<'static>,
+    }
+
+    impl Container {
+        fn new(tap: File) -> Self {
+            let mut device = TapDevice {
+                tap,
+                received: VecDeque::new(),
+            };
+            let config = Config::new(CONTAINER_MAC.into());
+            let mut iface = Interface::new(config, &mut device, smoltcp::time::Instant::now());
+            iface.update_ip_addrs(|addresses| {
+                addresses
+                    .push(IpCidr::new(
+                        IpAddress::Ipv4(CONTAINER_ADDRESS),
+                        PREFIX_LENGTH,
+                    ))
+                    .unwrap();
+            });
+
+            Container {
+                device,
+                iface,
+                sockets: SocketSet::new(Vec::new()),
+            }
+        }
+
+        /// Runs the container side of the link until `done` gives a result.
+        fn poll_until<T>(
+            &mut self,
+            mut done: impl FnMut(&mut SocketSet<'static>) -> Option<T>,
+        ) -> T {
+            let deadline = Instant::now() + Duration::from_secs(10);
+            let mut frame = vec![0u8; 65536];
+
+            loop {
+                while let Ok(length) = (&self.device.tap).read(&mut frame) {
+                    self.device.received.push_back(frame[..length].to_vec());
+                }
+                self.iface.poll(
+                    smoltcp::time::Instant::now(),
+                    &mut self.device,
+                    &mut self.sockets,
+                );
+                if let Some(result) = done(&mut self.sockets) {
+                    return result;
+                }
+
+                assert!(Instant::now() < deadline, "timed out");
+                std::thread::sleep(Duration::from_millis(1));
+            }
+        }
+
+        /// Sends `datagram` from `port` to `destination`, returns the reply and
+        /// where it came from.
+        fn udp_exchange(
+            &mut self,
+            port: u16,
+            destination: SocketAddrV4,
+            datagram: &[u8],
+        ) -> (Vec<u8>, IpEndpoint) {
+            let buffer = || {
+                udp::PacketBuffer::new(
+                    vec![udp::PacketMetadata::EMPTY; UDP_BUFFER_PACKETS],
+                    vec![0; UDP_BUFFER_SIZE],
+                )
+            };
+            let mut socket = udp::Socket::new(buffer(), buffer());
+            socket.bind(port).unwrap();
+            socket.send_slice(datagram, endpoint(destination)).unwrap();
+            let handle = self.sockets.add(socket);
+
+            self.poll_until(|sockets| {
+                let socket = sockets.get_mut::<udp::Socket>(handle);
+                socket
+                    .recv()
+                    .ok()
+                    .map(|(data, metadata)| (data.to_vec(), metadata.endpoint))
+            })
+        }
+    }
+
+    /// Starts a `Slirp` whose TAP device is the other end of the returned
+    /// container's link.
+    fn start(nameserver: SocketAddr) -> Container {
+        let (tap, container) = socketpair(
+            AddressFamily::Unix,
+            SockType::SeqPacket,
+            None,
+            SockFlag::SOCK_NONBLOCK | SockFlag::SOCK_CLOEXEC,
+        )
+        .unwrap();
+        let tap = File::from(tap);
+        std::thread::spawn(move || Slirp::new(tap, nameserver).run());
+
+        Container::new(File::from(container))
+    }
+
+    /// A host UDP socket on loopback answering one datagram in capitals.
+    fn udp_echo() -> SocketAddr {
+        let host = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let address = host.local_addr().unwrap();
+        std::thread::spawn(move || {
+            let mut datagram = [0u8; 64];
+            let (length, peer) = host.recv_from(&mut datagram).unwrap();
+            host.send_to(&datagram[..length].to_ascii_uppercase(), peer)
+                .unwrap();
+        });
+
+        address
+    }
+
+    #[test]
+    fn host_destinations() {
+        let nameserver = SocketAddr::from(([192, 0, 2, 53], 53));
+        let public = SocketAddrV4::new(Ipv4Addr::new(198, 51, 100, 1), 443);
+
+        for (destination, expected) in [
+            (
+                SocketAddrV4::new(GATEWAY, 8080),
+                Some(SocketAddr::from((Ipv4Addr::LOCALHOST, 8080))),
+            ),
+            (SocketAddrV4::new(NAMESERVER, 53), Some(nameserver)),
+            // Only DNS is forwarded to the nameserver.
+            (SocketAddrV4::new(NAMESERVER, 80), None),
+            (SocketAddrV4::new(Ipv4Addr::new(10, 0, 2, 50), 80), None),
+            (public, Some(public.into())),
+        ] {
+            assert_eq!(
+                host_destination(nameserver, destination),
+                expected,
+                "{destination}"
+            );
+        }
+    }
+
+    #[test]
+    fn gateway_tcp_reaches_host_loopback() {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let host = std::thread::spawn(move || {
+            let (mut stream, peer) = listener.accept().unwrap();
+            let mut request = [0u8; 4];
+            stream.read_exact(&mut request).unwrap();
+            stream.write_all(&request.to_ascii_uppercase()).unwrap();
+            peer
+        });
+
+        let mut container = start(SocketAddr::from((Ipv4Addr::LOCALHOST, 53)));
+        let mut socket = tcp::Socket::new(
+            tcp::SocketBuffer::new(vec![0; TCP_BUFFER_SIZE]),
+            tcp::SocketBuffer::new(vec![0; TCP_BUFFER_SIZE]),
+        );
+        socket
+            .connect(
+                container.iface.context(),
+                endpoint(SocketAddrV4::new(GATEWAY, port)),
+                40000,
+            )
+            .unwrap();
+        let handle = container.sockets.add(socket);
+
+        let mut sent = false;
+        let mut response = Vec::new();
+        container.poll_until(|sockets| {
+            let socket = sockets.get_mut::<tcp::Socket>(handle);
+            if !sent && socket.may_send() {
+                socket.send_slice(b"ping").unwrap();
+                sent = true;
+            }
+            if socket.can_recv() {
+                socket
+                    .recv(|data| {
+                        response.extend_from_slice(data);
+                        (data.len(), ())
+                    })
+                    .unwrap();
+            }
+
+            (response.len() == 4).then_some(())
+        });
+
+        assert_eq!(response, b"PING");
+        assert!(host.join().unwrap().ip().is_loopback());
+    }
+
+    #[test]
+    fn gateway_udp_reaches_host_loopback() {
+        let host = udp_echo();
+        let mut container = start(SocketAddr::from((Ipv4Addr::LOCALHOST, 53)));
+
+        let gateway = SocketAddrV4::new(GATEWAY, host.port());
+        let (reply, source) = container.udp_exchange(40001, gateway, b"ping");
+
+        assert_eq!(reply, b"PING");
+        assert_eq!(source, endpoint(gateway));
+    }
+
+    #[test]
+    fn dns_goes_to_host_nameserver() {
+        let nameserver = udp_echo();
+        let mut container = start(nameserver);
+
+        let dns = SocketAddrV4::new(NAMESERVER, 53);
+        let (reply, source) = container.udp_exchange(40002, dns, b"query");
+
+        assert_eq!(reply, b"QUERY");
+        assert_eq!(source, endpoint(dns));
+    }
+}
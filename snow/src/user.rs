@@ -0,0 +1,109 @@
+use anyhow::{bail, Context, Result};
+use nix::unistd::{self, Gid, Uid};
+
+/// An entry of a passwd or group file, split on `:`.
+fn find_entry(path: &str, name_or_id: &str, id_field: usize) -> Result<Option<Vec<String>>> {
+    let database = match std::fs::read_to_string(path) {
+        Ok(database) => database,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(err) => return Err(err).with_context(|| format!("failed reading {path}")),
+    };
+
+    Ok(database
+        .lines()
+        .map(|line| line.split(':').map(str::to_string).collect::<Vec<_>>())
+        .find(|fields| {
+            fields.len() > id_field && (fields[0] == name_or_id || fields[id_field] == name_or_id)
+        }))
+}
+
+/// Who the container process runs as.
+#[derive(Debug)]
+pub struct User {
+    pub uid: Uid,
+    pub gid: Gid,
+    /// Supplementary groups, the primary one included.
+    pub groups: Vec<Gid>,
+    pub home: String,
+}
+
+/// The groups of /etc/group that list `name` as a member.
+fn supplementary_groups(name: &str) -> Result<Vec<Gid>> {
+    let database = match std::fs::read_to_string("/etc/group") {
+        Ok(database) => database,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(vec![]),
+        Err(err) => return Err(err).context("failed reading /etc/group"),
+    };
+
+    Ok(database
+        .lines()
+        .map(|line| line.split(':').collect::<Vec<_>>())
+        .filter(|fields| fields.len() > 3 && fields[3].split(',').any(|member| member == name))
+        .filter_map(|fields| fields[2].parse().ok())
+        .map(Gid::from_raw)
+        .collect())
+}
+
+/// Resolves a Docker style `user[:group]`, where both can be names or ids, against
+/// the user databases of the current root. Like Docker, a user missing from
+/// /etc/passwd gets group 0 and `/` as its home.
+pub fn resolve(spec: &str) -> Result<User> {
+    let (user, group) = match spec.split_once(':') {
+        Some((user, group)) => (user, Some(group)),
+        None => (spec, None),
+    };
+
+    let passwd_entry = find_entry("/etc/passwd", user, 2)?;
+    let uid = match (&passwd_entry, user.parse::<u32>()) {
+        (Some(entry), _) => entry[2].parse::<u32>()?,
+        (None, Ok(uid)) => uid,
+        (None, Err(_)) => bail!("user {user} not found in /etc/passwd"),
+    };
+
+    let gid = match group {
+        Some(group) => match (find_entry("/etc/group", group, 2)?, group.parse::<u32>()) {
+            (Some(entry), _) => entry[2].parse::<u32>()?,
+            (None, Ok(gid)) => gid,
+            (None, Err(_)) => bail!("group {group} not found in /etc/group"),
+        },
+        None => match &passwd_entry {
+            Some(entry) if entry.len() > 3 => entry[3].parse::<u32>()?,
+            _ => 0,
+        },
+    };
+    let gid = Gid::from_raw(gid);
+
+    let mut groups = vec![gid];
+    if let Some(entry) = &passwd_entry {
+        for supplementary in supplementary_groups(&entry[0])? {
+            if !groups.contains(&supplementary) {
+                groups.push(supplementary);
+            }
+        }
+    }
+
+    let home = match &passwd_entry {
+        Some(entry) if entry.len() > 5 && !entry[5].is_empty() => entry[5].clone(),
+        _ => "/".to_string(),
+    };
+
+    Ok(User {
+        uid: Uid::from_raw(uid),
+        gid,
+        groups,
+        home,
+    })
+}
+
+/// Becomes `user`, trading the supplementary groups we had as root for its own.
+pub fn switch(user: &User) -> Result<()> {
+    // A rootless container with a single id mapping had to give up setgroups.
+    let setgroups = std::fs::read_to_string("/proc/self/setgroups").unwrap_or_default();
+    if setgroups.trim() != "deny" {
+        unistd::setgroups(&user.groups)?;
+    }
+    unistd::setgid(user.gid)?;
+    unistd::setuid(user.uid)?;
+
+    Ok(())
+}
@@ -0,0 +1,235 @@
+use anyhow::{bail, Context, Result};
+use clap::ValueEnum;
+use log::{debug, error, info};
+use nix::sched::{unshare, CloneFlags};
+use nix::sys::wait::{waitpid, WaitStatus};
+use nix::unistd::{self, ForkResult, Gid, Pid, Uid, User};
+use std::os::fd::AsRawFd;
+use std::os::unix::fs::MetadataExt;
+use std::path::Path;
+use std::process::Command;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum IdMap {
+    /// Subordinate id ranges when configured for the user, a single id otherwise.
+    Auto,
+    /// Only map our own uid and gid to root.
+    Single,
+    /// Map root to us and the rest to our /etc/subuid and /etc/subgid ranges
+    /// through newuidmap and newgidmap.
+    Subid,
+}
+
+/// A `start:count` range of subordinate ids out of /etc/subuid or /etc/subgid.
+type SubidRange = (u64, u64);
+
+fn find_subid_range(path: &str, user: &User) -> Result<Option<SubidRange>> {
+    let database = match std::fs::read_to_string(path) {
+        Ok(database) => database,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(err) => return Err(err).with_context(|| format!("failed reading {path}")),
+    };
+
+    for line in database.lines() {
+        let fields: Vec<&str> = line.trim().split(':').collect();
+        if let [owner, start, count] = fields[..] {
+            if owner == user.name || owner == user.uid.to_string() {
+                return Ok(Some((start.parse()?, count.parse()?)));
+            }
+        }
+    }
+
+    Ok(None)
+}
+
+fn find_subid_ranges(uid: Uid) -> Result<Option<(SubidRange, SubidRange)>> {
+    let user = User::from_uid(uid)?.with_context(|| format!("uid {uid} has no passwd entry"))?;
+
+    let subuid = find_subid_range("/etc/subuid", &user)?;
+    let subgid = find_subid_range("/etc/subgid", &user)?;
+
+    Ok(subuid.zip(subgid))
+}
+
+fn id_mappers_installed() -> bool {
+    let path = std::env::var("PATH").unwrap_or_default();
+    ["newuidmap", "newgidmap"].iter().all(|program| {
+        path.split(':')
+            .any(|dir| !dir.is_empty() && Path::new(dir).join(program).is_file())
+    })
+}
+
+fn run_id_mapper(program: &str, pid: Pid, id: u32, range: SubidRange) -> Result<()> {
+    let status = Command::new(program)
+        .args([
+            pid.to_string(),
+            "0".to_string(),
+            id.to_string(),
+            "1".to_string(),
+            "1".to_string(),
+            range.0.to_string(),
+            range.1.to_string(),
+        ])
+        .status()
+        .with_context(|| format!("failed running {program}"))?;
+
+    if !status.success() {
+        bail!("{program} failed with {status}");
+    }
+
+    Ok(())
+}
+
+/// newuidmap and newgidmap are setuid helpers which only do their thing from
+/// the parent user namespace, so a forked child waits for us to unshare and
+/// then writes our maps.
+fn enter_with_subids(uid: Uid, gid: Gid, subuid: SubidRange, subgid: SubidRange) -> Result<()> {
+    let pid = unistd::getpid();
+    let (ready_read, ready_write) = unistd::pipe()?;
+
+    // SAFETY: we are still single threaded at this point.
+    match unsafe { unistd::fork()? } {
+        ForkResult::Child => {
+            drop(ready_write);
+
+            let mut ready = [0u8; 1];
+            let result = match unistd::read(ready_read.as_raw_fd(), &mut ready) {
+                Ok(1) => run_id_mapper("newuidmap", pid, uid.as_raw(), subuid)
+                    .and_then(|()| run_id_mapper("newgidmap", pid, gid.as_raw(), subgid)),
+                // The parent failed to unshare, nothing to map.
+                _ => Ok(()),
+            };
+
+            if let Err(err) = &result {
+                error!("failed mapping ids: {err:?}");
+            }
+            std::process::exit(i32::from(result.is_err()));
+        }
+        ForkResult::Parent { child } => {
+            drop(ready_read);
+
+            let unshared = unshare(CloneFlags::CLONE_NEWUSER);
+            if unshared.is_ok() {
+                unistd::write(&ready_write, &[1])?;
+            }
+            drop(ready_write);
+
+            let status = waitpid(child, None)?;
+            unshared?;
+
+            if status != WaitStatus::Exited(child, 0) {
+                bail!("failed mapping subordinate ids, {status:?}");
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn enter_with_single_id(uid: Uid, gid: Gid) -> Result<()> {
+    unshare(CloneFlags::CLONE_NEWUSER)?;
+
+    // An unprivileged process may only map a gid once it gave up setgroups.
+    std::fs::write("/proc/self/setgroups", "deny")?;
+    std::fs::write("/proc/self/uid_map", format!("0 {uid} 1"))?;
+    std::fs::write("/proc/self/gid_map", format!("0 {gid} 1"))?;
+
+    Ok(())
+}
+
+/// Enters a new user namespace in which we are root, which is all the
+/// privileges the rest of the setup needs apart from loop and dm devices.
+pub fn enter(id_map: IdMap) -> Result<()> {
+    let uid = Uid::current();
+    let gid = Gid::current();
+
+    let subid_ranges = match id_map {
+        IdMap::Single => None,
+        IdMap::Subid => Some(
+            find_subid_ranges(uid)?
+                .with_context(|| format!("uid {uid} has no /etc/subuid and /etc/subgid ranges"))?,
+        ),
+        IdMap::Auto if !id_mappers_installed() => None,
+        IdMap::Auto => match find_subid_ranges(uid) {
+            Ok(ranges) => ranges,
+            Err(err) => {
+                debug!("not using subordinate ids: {err:?}");
+                None
+            }
+        },
+    };
+
+    match subid_ranges {
+        Some((subuid, subgid)) => {
+            info!(
+                "mapping root to uid {uid} gid {gid}, subordinate uids {}+{} gids {}+{}",
+                subuid.0, subuid.1, subgid.0, subgid.1
+            );
+            enter_with_subids(uid, gid, subuid, subgid)
+        }
+        None => {
+            info!("mapping root to uid {uid} gid {gid}");
+            enter_with_single_id(uid, gid)
+        }
+    }
+}
+
+/// Whether `id` is mapped in the id map file `path` of our user namespace.
+fn is_mapped(path: &str, id: u32) -> Result<bool> {
+    let map = std::fs::read_to_string(path).with_context(|| format!("failed reading {path}"))?;
+
+    for line in map.lines() {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        if let [inside, _, count] = fields[..] {
+            let inside: u64 = inside.parse()?;
+            if (inside..inside + count.parse::<u64>()?).contains(&u64::from(id)) {
+                return Ok(true);
+            }
+        }
+    }
+
+    Ok(false)
+}
+
+fn overflow_id(path: &str) -> Result<u32> {
+    let id = std::fs::read_to_string(path).with_context(|| format!("failed reading {path}"))?;
+    Ok(id.trim().parse()?)
+}
+
+/// Fails on the first file below `root` whose owner our user namespace
+/// doesn't map. The kernel shows those as owned by the overflow ids, nobody,
+/// which is what anything copying the files would make them.
+pub fn ensure_owners_mapped(root: &Path) -> Result<()> {
+    let overflow_uid = overflow_id("/proc/sys/kernel/overflowuid")?;
+    let overflow_gid = overflow_id("/proc/sys/kernel/overflowgid")?;
+    // Mapped, the overflow ids can't be told apart from files really owned by
+    // them.
+    let check_uid = !is_mapped("/proc/self/uid_map", overflow_uid)?;
+    let check_gid = !is_mapped("/proc/self/gid_map", overflow_gid)?;
+    if !check_uid && !check_gid {
+        return Ok(());
+    }
+
+    let mut directories = vec![root.to_path_buf()];
+    while let Some(directory) = directories.pop() {
+        for entry in std::fs::read_dir(&directory)
+            .with_context(|| format!("failed reading {}", directory.display()))?
+        {
+            let entry = entry?;
+            let metadata = entry.metadata()?;
+            if (check_uid && metadata.uid() == overflow_uid)
+                || (check_gid && metadata.gid() == overflow_gid)
+            {
+                bail!(
+                    "/{} belongs to an id outside the user namespace, use --id-map subid or root",
+                    entry.path().strip_prefix(root)?.display()
+                );
+            }
+            if metadata.is_dir() {
+                directories.push(entry.path());
+            }
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,379 @@
+use anyhow::{Context, Result};
+use log::warn;
+use nix::sys::stat::{self, SFlag};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::fs::File;
+use std::io::prelude::*;
+use std::os::unix::fs::MetadataExt;
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+
+// We use the same parameters `veritysetup format` defaults to, a format 1 hash
+// tree with no superblock, 4K data and hash blocks and a salted sha256.
+const BLOCK_SIZE: usize = 4096;
+const DIGEST_SIZE: usize = 32;
+const HASHES_PER_BLOCK: usize = BLOCK_SIZE / DIGEST_SIZE;
+const SALT_SIZE: usize = 32;
+
+/// Everything the kernel needs besides the hash tree itself, recorded in the
+/// image metadata so it is covered by the image signature.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct VerityMetadata {
+    pub root_hash: String,
+    pub salt: String,
+    pub data_blocks: u64,
+}
+
+fn hash_block(salt: &[u8], block: &[u8]) -> [u8; DIGEST_SIZE] {
+    let mut hasher = Sha256::new();
+    hasher.update(salt);
+    hasher.update(block);
+
+    hasher.finalize().into()
+}
+
+fn pack_hashes(hashes: &[[u8; DIGEST_SIZE]]) -> Vec<u8> {
+    let mut blocks = Vec::with_capacity(hashes.len().div_ceil(HASHES_PER_BLOCK) * BLOCK_SIZE);
+
+    for chunk in hashes.chunks(HASHES_PER_BLOCK) {
+        for hash in chunk {
+            blocks.extend_from_slice(hash);
+        }
+        blocks.resize(blocks.len().next_multiple_of(BLOCK_SIZE), 0);
+    }
+
+    blocks
+}
+
+/// Builds the hash tree over `data`, whose last block is hashed zero padded
+/// just like the page aligned payload section it is read from at runtime.
+pub fn hash_tree(data: impl Read) -> Result<(Vec<u8>, VerityMetadata)> {
+    let mut salt = [0u8; SALT_SIZE];
+    rand::Rng::fill(&mut rand::thread_rng(), &mut salt);
+
+    hash_tree_with_salt(data, salt)
+}
+
+fn hash_tree_with_salt(
+    mut data: impl Read,
+    salt: [u8; SALT_SIZE],
+) -> Result<(Vec<u8>, VerityMetadata)> {
+    let mut hashes = Vec::new();
+    let mut block = vec![0u8; BLOCK_SIZE];
+    loop {
+        block.fill(0);
+        let mut filled = 0;
+        while filled < BLOCK_SIZE {
+            match data.read(&mut block[filled..])? {
+                0 => break,
+                read => filled += read,
+            }
+        }
+
+        if filled == 0 {
+            break;
+        }
+
+        hashes.push(hash_block(&salt, &block));
+    }
+
+    let data_blocks = hashes.len() as u64;
+
+    // The kernel stores the levels top down, the level right below the root first.
+    let mut levels = Vec::new();
+    let mut root_hash = hashes.first().copied().unwrap_or_default();
+    while hashes.len() > 1 {
+        let level = pack_hashes(&hashes);
+        hashes = level
+            .chunks(BLOCK_SIZE)
+            .map(|block| hash_block(&salt, block))
+            .collect();
+        root_hash = hashes[0];
+        levels.push(level);
+    }
+    levels.reverse();
+
+    Ok((
+        levels.concat(),
+        VerityMetadata {
+            root_hash: hex::encode(root_hash),
+            salt: hex::encode(salt),
+            data_blocks,
+        },
+    ))
+}
+
+// See include/uapi/linux/dm-ioctl.h
+const DM_NAME_LEN: usize = 128;
+const DM_UUID_LEN: usize = 129;
+const DM_READONLY_FLAG: u32 = 1 << 0;
+const DM_DEFERRED_REMOVE: u32 = 1 << 17;
+const DM_IOCTL_BUFFER_SIZE: usize = 16 * 1024;
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct DmIoctl {
+    version: [u32; 3],
+    data_size: u32,
+    data_start: u32,
+    target_count: u32,
+    open_count: i32,
+    flags: u32,
+    event_nr: u32,
+    padding: u32,
+    dev: u64,
+    name: [u8; DM_NAME_LEN],
+    uuid: [u8; DM_UUID_LEN],
+    data: [u8; 7],
+}
+
+#[repr(C)]
+struct DmTargetSpec {
+    sector_start: u64,
+    length: u64,
+    status: i32,
+    next: u32,
+    target_type: [u8; 16],
+}
+
+nix::ioctl_readwrite!(dm_dev_create, 0xfd, 3, DmIoctl);
+nix::ioctl_readwrite!(dm_dev_remove, 0xfd, 4, DmIoctl);
+nix::ioctl_readwrite!(dm_dev_suspend, 0xfd, 6, DmIoctl);
+nix::ioctl_readwrite!(dm_table_load, 0xfd, 9, DmIoctl);
+
+type DmIoctlFn = unsafe fn(i32, *mut DmIoctl) -> nix::Result<i32>;
+
+/// Issues a device mapper ioctl on the device `name` with an optional single
+/// target, returns the dm_ioctl header the kernel handed back.
+fn dm_ioctl(
+    control: &File,
+    ioctl: DmIoctlFn,
+    name: &str,
+    flags: u32,
+    target: Option<(&str, u64, &str)>,
+) -> Result<DmIoctl> {
+    // u64 elements keep the header and the target spec properly aligned.
+    let mut buffer = vec![0u64; DM_IOCTL_BUFFER_SIZE / 8];
+    let header_size = std::mem::size_of::<DmIoctl>();
+
+    let mut header = DmIoctl {
+        version: [4, 0, 0],
+        data_size: DM_IOCTL_BUFFER_SIZE as u32,
+        data_start: header_size as u32,
+        target_count: 0,
+        open_count: 0,
+        flags,
+        event_nr: 0,
+        padding: 0,
+        dev: 0,
+        name: [0; DM_NAME_LEN],
+        uuid: [0; DM_UUID_LEN],
+        data: [0; 7],
+    };
+    header.name[..name.len()].copy_from_slice(name.as_bytes());
+
+    let bytes = buffer.as_mut_ptr() as *mut u8;
+    if let Some((target_type, sectors, params)) = target {
+        header.target_count = 1;
+
+        let mut spec = DmTargetSpec {
+            sector_start: 0,
+            length: sectors,
+            status: 0,
+            next: 0,
+            target_type: [0; 16],
+        };
+        spec.target_type[..target_type.len()].copy_from_slice(target_type.as_bytes());
+
+        let params_offset = header_size + std::mem::size_of::<DmTargetSpec>();
+        assert!(params_offset + params.len() < DM_IOCTL_BUFFER_SIZE);
+
+        // SAFETY: both writes are in bounds of the buffer, which is zeroed so the
+        // parameters string stays nul terminated.
+        unsafe {
+            std::ptr::write(bytes.add(header_size) as *mut DmTargetSpec, spec);
+            std::ptr::copy_nonoverlapping(params.as_ptr(), bytes.add(params_offset), params.len());
+        }
+    }
+
+    // SAFETY: the buffer is large enough for the header and the data_size we
+    // announce to the kernel.
+    unsafe {
+        std::ptr::write(bytes as *mut DmIoctl, header);
+        ioctl(control.as_raw_fd(), bytes as *mut DmIoctl)
+            .with_context(|| format!("device mapper ioctl on {name} failed"))?;
+
+        Ok(std::ptr::read(bytes as *const DmIoctl))
+    }
+}
+
+fn device_number(device_path: &Path) -> Result<String> {
+    let rdev = std::fs::metadata(device_path)?.rdev();
+
+    Ok(format!("{}:{}", stat::major(rdev), stat::minor(rdev)))
+}
+
+/// A dm-verity device we created. Dropping it has the kernel remove it once
+/// nothing uses it anymore, right away if nothing does.
+pub struct VerityDevice {
+    name: String,
+}
+
+impl Drop for VerityDevice {
+    fn drop(&mut self) {
+        if let Err(err) = remove_deferred(&self.name) {
+            warn!("failed removing dm-verity device {}: {err:?}", self.name);
+        }
+    }
+}
+
+/// Creates a read only dm-verity target named `name` on top of `data_device`,
+/// which is checked against the hash tree on `hash_device`, and a block device
+/// node for it at `node_path`.
+pub fn setup(
+    name: &str,
+    data_device: &Path,
+    hash_device: &Path,
+    verity: &VerityMetadata,
+    node_path: &Path,
+) -> Result<VerityDevice> {
+    let control = File::options()
+        .read(true)
+        .write(true)
+        .open("/dev/mapper/control")?;
+
+    let table = format!(
+        "1 {} {} {BLOCK_SIZE} {BLOCK_SIZE} {} 0 sha256 {} {}",
+        device_number(data_device)?,
+        device_number(hash_device)?,
+        verity.data_blocks,
+        verity.root_hash,
+        verity.salt
+    );
+    let sectors = verity.data_blocks * (BLOCK_SIZE as u64 / 512);
+
+    let created = dm_ioctl(&control, dm_dev_create, name, 0, None)?;
+    let device = VerityDevice {
+        name: name.to_string(),
+    };
+    dm_ioctl(
+        &control,
+        dm_table_load,
+        name,
+        DM_READONLY_FLAG,
+        Some(("verity", sectors, &table)),
+    )?;
+    // Resuming a device is a suspend without the suspend flag.
+    dm_ioctl(&control, dm_dev_suspend, name, 0, None)?;
+
+    // The kernel hands out dev_t in its own encoding, see new_decode_dev().
+    let major = (created.dev >> 8) & 0xfff;
+    let minor = (created.dev & 0xff) | ((created.dev >> 12) & 0xfff00);
+
+    // We can't count on udev creating /dev/mapper/<name> for us.
+    stat::mknod(
+        node_path,
+        SFlag::S_IFBLK,
+        stat::Mode::S_IRUSR,
+        stat::makedev(major, minor),
+    )?;
+
+    Ok(device)
+}
+
+/// Makes the kernel remove the device `name` once nothing uses it anymore.
+fn remove_deferred(name: &str) -> Result<()> {
+    let control = File::options()
+        .read(true)
+        .write(true)
+        .open("/dev/mapper/control")?;
+
+    dm_ioctl(&control, dm_dev_remove, name, DM_DEFERRED_REMOVE, None)?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Salt bytes 0 to 31.
+    fn salt() -> [u8; SALT_SIZE] {
+        std::array::from_fn(|i| i as u8)
+    }
+
+    /// 130 blocks, the last one partial, enough for a tree of two levels.
+    fn data() -> Vec<u8> {
+        (0..129 * BLOCK_SIZE + 100)
+            .map(|i| ((i * 7 + i / BLOCK_SIZE) % 251) as u8)
+            .collect()
+    }
+
+    fn sha256(data: &[u8]) -> [u8; DIGEST_SIZE] {
+        Sha256::digest(data).into()
+    }
+
+    fn salted(block: &[u8]) -> [u8; DIGEST_SIZE] {
+        sha256(&[&salt()[..], block].concat())
+    }
+
+    // Computed apart from this code from the format 1 layout, salt first and
+    // hash blocks zero padded, levels stored top down.
+    #[test]
+    fn known_root_hash() {
+        let (tree, metadata) = hash_tree_with_salt(data().as_slice(), salt()).unwrap();
+
+        assert_eq!(metadata.data_blocks, 130);
+        assert_eq!(metadata.salt, hex::encode(salt()));
+        assert_eq!(
+            metadata.root_hash,
+            "09ba747b130d941c681ae343821f850176f367788a4715789f876de529515bb7"
+        );
+        assert_eq!(
+            hex::encode(sha256(&tree)),
+            "810152ffdc1131bdbc67441a439b12475292367c37c2ae03993a211a07e2095c"
+        );
+    }
+
+    #[test]
+    fn tree_layout() {
+        let data = data();
+        let (tree, metadata) = hash_tree_with_salt(data.as_slice(), salt()).unwrap();
+
+        // The root block, then the two blocks of data hashes.
+        let blocks: Vec<&[u8]> = tree.chunks(BLOCK_SIZE).collect();
+        assert_eq!(blocks.len(), 3);
+        assert_eq!(metadata.root_hash, hex::encode(salted(blocks[0])));
+
+        let mut last = data[129 * BLOCK_SIZE..].to_vec();
+        last.resize(BLOCK_SIZE, 0);
+        let data_hashes: Vec<[u8; DIGEST_SIZE]> = data
+            .chunks(BLOCK_SIZE)
+            .take(129)
+            .map(salted)
+            .chain([salted(&last)])
+            .collect();
+        assert_eq!(blocks[1], data_hashes[..HASHES_PER_BLOCK].concat());
+        let mut second = data_hashes[HASHES_PER_BLOCK..].concat();
+        second.resize(BLOCK_SIZE, 0);
+        assert_eq!(blocks[2], second);
+
+        let mut top = [salted(blocks[1]), salted(blocks[2])].concat();
+        top.resize(BLOCK_SIZE, 0);
+        assert_eq!(blocks[0], top);
+    }
+
+    #[test]
+    fn single_block_is_the_root() {
+        let data = data();
+        let (tree, metadata) = hash_tree_with_salt(&data[..BLOCK_SIZE], salt()).unwrap();
+
+        assert!(tree.is_empty());
+        assert_eq!(
+            metadata.root_hash,
+            "998d9aa5928734dc51fca553d4a9ad17adc0bad9b9d4e2a72fce3105db3d6199"
+        );
+        assert_eq!(metadata.root_hash, hex::encode(salted(&data[..BLOCK_SIZE])));
+    }
+}
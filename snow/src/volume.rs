@@ -0,0 +1,285 @@
+use crate::mount;
+use anyhow::{bail, Context, Result};
+use log::info;
+use nix::mount::MsFlags;
+use std::collections::VecDeque;
+use std::ffi::OsString;
+use std::fs::File;
+use std::path::{Component, Path, PathBuf};
+use std::str::FromStr;
+
+/// How many symlinks resolving a container path may go through, like the
+/// kernel's limit.
+const MAX_SYMLINKS: usize = 40;
+
+/// Whether mounts made on the host below a volume show up in the container.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Propagation {
+    Private,
+    Slave,
+}
+
+impl Propagation {
+    fn flags(self) -> MsFlags {
+        MsFlags::MS_REC
+            | match self {
+                Propagation::Private => MsFlags::MS_PRIVATE,
+                Propagation::Slave => MsFlags::MS_SLAVE,
+            }
+    }
+}
+
+/// A host file or directory bound into the container,
+/// `hostpath:containerpath[:ro|rw][,rslave|rprivate]`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Volume {
+    pub host_path: PathBuf,
+    pub container_path: PathBuf,
+    pub read_only: bool,
+    pub propagation: Propagation,
+}
+
+impl FromStr for Volume {
+    type Err = anyhow::Error;
+
+    fn from_str(volume: &str) -> Result<Self> {
+        let mut fields = volume.splitn(3, ':');
+        let (Some(host_path), Some(container_path)) = (fields.next(), fields.next()) else {
+            bail!("expected hostpath:containerpath[:ro|rw][,rslave|rprivate]");
+        };
+        if !container_path.starts_with('/') {
+            bail!("container path {container_path} is not absolute");
+        }
+
+        let mut read_only = false;
+        let mut propagation = Propagation::Private;
+        for option in fields
+            .next()
+            .into_iter()
+            .flat_map(|options| options.split(','))
+        {
+            match option {
+                "ro" => read_only = true,
+                "rw" => read_only = false,
+                "rprivate" | "private" => propagation = Propagation::Private,
+                "rslave" | "slave" => propagation = Propagation::Slave,
+                // Our mount ns only follows the host's, the container's
+                // mounts would have nowhere to propagate to.
+                "rshared" | "shared" => bail!("{option} isn't supported, use rslave"),
+                _ => bail!("unknown volume option {option}"),
+            }
+        }
+
+        Ok(Volume {
+            host_path: PathBuf::from(host_path),
+            container_path: PathBuf::from(container_path),
+            read_only,
+            propagation,
+        })
+    }
+}
+
+/// Joins `path` to `root` the way the container will see it, symlinks in the
+/// image resolved against `root` rather than the host's root and `..` never
+/// leaving it. Parts that don't exist yet are taken as they are.
+/// Nothing runs in the container yet, so nothing swaps them under us.
+pub fn secure_join(root: &Path, path: &Path) -> Result<PathBuf> {
+    let mut resolved = PathBuf::new();
+    let mut remaining: VecDeque<OsString> = path
+        .components()
+        .map(|component| component.as_os_str().to_owned())
+        .collect();
+    let mut symlinks = 0;
+
+    while let Some(component) = remaining.pop_front() {
+        match Path::new(&component).components().next() {
+            Some(Component::Normal(name)) => {
+                let candidate = root.join(&resolved).join(name);
+                match std::fs::symlink_metadata(&candidate) {
+                    Ok(metadata) if metadata.file_type().is_symlink() => {
+                        symlinks += 1;
+                        if symlinks > MAX_SYMLINKS {
+                            bail!("too many symlinks resolving {}", path.display());
+                        }
+
+                        let target = std::fs::read_link(&candidate)?;
+                        if target.is_absolute() {
+                            resolved.clear();
+                        }
+                        for component in target.components().rev() {
+                            remaining.push_front(component.as_os_str().to_owned());
+                        }
+                    }
+                    _ => resolved.push(name),
+                }
+            }
+            Some(Component::ParentDir) => {
+                resolved.pop();
+            }
+            _ => {}
+        }
+    }
+
+    Ok(root.join(resolved))
+}
+
+/// Creates what `host_path` is bound onto at `target`, a directory or an empty
+/// file to match it.
+fn create_mountpoint(host_path: &Path, target: &Path) -> Result<()> {
+    if host_path.is_dir() {
+        std::fs::create_dir_all(target)?;
+        return Ok(());
+    }
+
+    if let Some(parent) = target.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    if !target.exists() {
+        File::create(target)?;
+    }
+
+    Ok(())
+}
+
+/// Binds `volumes` into the container rootfs at `target`.
+pub fn mount_volumes(target: &Path, volumes: &[Volume]) -> Result<()> {
+    for volume in volumes {
+        let host_path = volume
+            .host_path
+            .canonicalize()
+            .with_context(|| format!("volume source {} not found", volume.host_path.display()))?;
+        let mountpoint = secure_join(target, &volume.container_path)?;
+        info!(
+            "binding volume {} on {}",
+            host_path.display(),
+            mountpoint.display()
+        );
+
+        create_mountpoint(&host_path, &mountpoint).with_context(|| {
+            format!(
+                "failed creating mountpoint {}",
+                volume.container_path.display()
+            )
+        })?;
+        nix::mount::mount::<Path, Path, str, str>(
+            Some(&host_path),
+            &mountpoint,
+            None,
+            MsFlags::MS_BIND | MsFlags::MS_REC,
+            None,
+        )
+        .with_context(|| format!("failed binding volume {}", host_path.display()))?;
+
+        if volume.read_only {
+            mount::make_read_only_recursively(&mountpoint)?;
+        }
+        nix::mount::mount::<str, Path, str, str>(
+            None,
+            &mountpoint,
+            None,
+            volume.propagation.flags(),
+            None,
+        )?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::symlink;
+
+    /// An image rootfs with /etc and /usr/lib for the symlinks of the test
+    /// `name` to point into, emptied first.
+    fn scratch_root(name: &str) -> PathBuf {
+        let root = std::env::temp_dir().join(format!("snow-volume-{}-{name}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&root);
+        std::fs::create_dir_all(root.join("etc")).unwrap();
+        std::fs::create_dir_all(root.join("usr/lib")).unwrap();
+        root
+    }
+
+    #[test]
+    fn absolute_symlink_stays_in_root() {
+        let root = scratch_root("absolute");
+        symlink("/etc", root.join("config")).unwrap();
+
+        assert_eq!(
+            secure_join(&root, Path::new("/config/passwd")).unwrap(),
+            root.join("etc/passwd")
+        );
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn relative_symlink_climbs_no_higher_than_root() {
+        let root = scratch_root("relative");
+        symlink("../../etc", root.join("usr/lib/config")).unwrap();
+        symlink("../../../../../etc", root.join("usr/lib/escape")).unwrap();
+
+        assert_eq!(
+            secure_join(&root, Path::new("/usr/lib/config/passwd")).unwrap(),
+            root.join("etc/passwd")
+        );
+        assert_eq!(
+            secure_join(&root, Path::new("/usr/lib/escape/passwd")).unwrap(),
+            root.join("etc/passwd")
+        );
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn parent_dir_stays_in_root() {
+        let root = scratch_root("parent");
+
+        assert_eq!(
+            secure_join(&root, Path::new("/usr/../etc")).unwrap(),
+            root.join("etc")
+        );
+        assert_eq!(
+            secure_join(&root, Path::new("/../../etc")).unwrap(),
+            root.join("etc")
+        );
+        assert_eq!(
+            secure_join(&root, Path::new("usr/lib/../../..")).unwrap(),
+            root
+        );
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn dangling_symlink_is_followed_into_root() {
+        let root = scratch_root("dangling");
+        symlink("/nowhere/deep", root.join("dangling")).unwrap();
+
+        assert_eq!(
+            secure_join(&root, Path::new("/dangling/file")).unwrap(),
+            root.join("nowhere/deep/file")
+        );
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn symlinks_are_limited() {
+        let root = scratch_root("limit");
+        symlink("/loop", root.join("loop")).unwrap();
+        // link0 takes one more symlink than the limit to reach /etc.
+        for link in 0..MAX_SYMLINKS {
+            symlink(
+                format!("/link{}", link + 1),
+                root.join(format!("link{link}")),
+            )
+            .unwrap();
+        }
+        symlink("/etc", root.join(format!("link{MAX_SYMLINKS}"))).unwrap();
+
+        assert!(secure_join(&root, Path::new("/loop")).is_err());
+        assert_eq!(
+            secure_join(&root, Path::new("/link1")).unwrap(),
+            root.join("etc")
+        );
+        assert!(secure_join(&root, Path::new("/link0")).is_err());
+        std::fs::remove_dir_all(root).unwrap();
+    }
+}